@@ -0,0 +1,200 @@
+// Difficulty metrics for a mine layout.
+
+use std::collections::HashSet;
+
+use game::Game;
+use topology::Pos;
+
+// Edge length of the square regions (within each layer) whose mine
+// density is reported
+const REGION_SIZE: usize = 8;
+
+
+pub struct Analysis {
+    // Minimum number of clicks needed to clear the field without
+    // flagging anything (the "3BV" value)
+    pub bbbv: usize,
+    // Number of cells unveiled by a click into each opening (i.e. into
+    // an area of cells without adjacent mines)
+    pub openings: Vec<usize>,
+    // Sizes of the groups of adjacent numbered cells that do not
+    // border any opening, so every one of them must be clicked
+    pub islands: Vec<usize>,
+    // Mines per cell in every region, indexed by layer, region row and
+    // region column (None for regions without any cells)
+    pub region_density: Vec<Vec<Vec<Option<f64>>>>,
+}
+
+
+impl Analysis {
+    // @game's mines must have been spread already
+    pub fn new(game: &Game) -> Self {
+        let topology = game.get_topology();
+        let dim = topology.get_dim();
+
+        let label = |pos: Pos| -> usize {
+            topology.neighbors(pos).into_iter()
+                    .map(|npos| game.mines_at(npos))
+                    .sum()
+        };
+
+        let is_opening = |pos: Pos| {
+            topology.is_active(pos) && game.mines_at(pos) == 0 &&
+                label(pos) == 0
+        };
+
+        // Flood-fill every opening, including the numbers at its border
+        let mut opened = HashSet::<Pos>::new();
+        let mut openings = Vec::<usize>::new();
+        for pos in topology.positions() {
+            if !is_opening(pos) || opened.contains(&pos) {
+                continue;
+            }
+
+            let mut cells = HashSet::<Pos>::new();
+            let mut stack = vec![pos];
+            cells.insert(pos);
+
+            while let Some(zpos) = stack.pop() {
+                for npos in topology.neighbors(zpos) {
+                    if cells.insert(npos) && is_opening(npos) {
+                        stack.push(npos);
+                    }
+                }
+            }
+
+            openings.push(cells.len());
+            opened.extend(cells);
+        }
+
+        // All other safe cells need a click each; group them by
+        // adjacency
+        let mut grouped = HashSet::<Pos>::new();
+        let mut islands = Vec::<usize>::new();
+        let mut lone_cells = 0;
+        for pos in topology.positions() {
+            if !topology.is_active(pos) || game.mines_at(pos) > 0 ||
+               opened.contains(&pos) || grouped.contains(&pos)
+            {
+                continue;
+            }
+
+            let mut size = 0;
+            let mut stack = vec![pos];
+            grouped.insert(pos);
+
+            while let Some(ipos) = stack.pop() {
+                size += 1;
+                for npos in topology.neighbors(ipos) {
+                    if game.mines_at(npos) == 0 &&
+                       !opened.contains(&npos) && grouped.insert(npos)
+                    {
+                        stack.push(npos);
+                    }
+                }
+            }
+
+            islands.push(size);
+            lone_cells += size;
+        }
+
+        let region_cols = dim.0.div_ceil(REGION_SIZE);
+        let region_rows = dim.1.div_ceil(REGION_SIZE);
+
+        // (mines, cells) per region
+        let mut regions =
+            vec![vec![vec![(0, 0); region_cols]; region_rows]; dim.2];
+        for pos in topology.positions() {
+            if topology.is_active(pos) {
+                let region = &mut regions[pos.2][pos.1 / REGION_SIZE]
+                                         [pos.0 / REGION_SIZE];
+                region.0 += game.mines_at(pos);
+                region.1 += 1;
+            }
+        }
+
+        let region_density =
+            regions.into_iter().map(|layer| {
+                layer.into_iter().map(|row| {
+                    row.into_iter().map(|(mines, cells)| {
+                        if cells > 0 {
+                            Some(mines as f64 / cells as f64)
+                        } else {
+                            None
+                        }
+                    }).collect()
+                }).collect()
+            }).collect();
+
+        Analysis {
+            bbbv: openings.len() + lone_cells,
+            openings: openings,
+            islands: islands,
+            region_density: region_density,
+        }
+    }
+
+    // Returns the lowest and highest mine density of all regions
+    fn density_range(&self) -> (f64, f64) {
+        let densities: Vec<f64> =
+            self.region_density.iter().flat_map(|layer| layer.iter())
+                               .flat_map(|row| row.iter())
+                               .filter_map(|&d| d)
+                               .collect();
+
+        (densities.iter().cloned().fold(f64::INFINITY, f64::min),
+         densities.iter().cloned().fold(0.0, f64::max))
+    }
+
+    // Returns all metrics in a single line
+    pub fn summary(&self) -> String {
+        let (min_density, max_density) = self.density_range();
+
+        format!("3BV: {}, openings: {} (largest: {}), islands: {} \
+                 (largest: {}), mine density: {:.0} – {:.0} %",
+                self.bbbv,
+                self.openings.len(),
+                self.openings.iter().max().unwrap_or(&0),
+                self.islands.len(),
+                self.islands.iter().max().unwrap_or(&0),
+                min_density * 100.0, max_density * 100.0)
+    }
+
+    // Returns all metrics in detail, one per line
+    pub fn report(&self) -> String {
+        let mut openings = self.openings.clone();
+        let mut islands = self.islands.clone();
+        openings.sort_by(|a, b| b.cmp(a));
+        islands.sort_by(|a, b| b.cmp(a));
+
+        let list = |sizes: &[usize]| -> String {
+            sizes.iter().map(|s| s.to_string()).collect::<Vec<String>>()
+                 .join(", ")
+        };
+
+        let mut text = format!("3BV: {}\n", self.bbbv);
+        text.push_str(&format!("Openings: {}\n", self.openings.len()));
+        text.push_str(&format!("  Sizes: {}\n", list(&openings)));
+        text.push_str(&format!("Islands: {}\n", self.islands.len()));
+        text.push_str(&format!("  Sizes: {}\n", list(&islands)));
+
+        text.push_str(&format!("Mine density per {0}×{0} region (%):\n",
+                               REGION_SIZE));
+        for (z, layer) in self.region_density.iter().enumerate() {
+            if self.region_density.len() > 1 {
+                text.push_str(&format!("  Layer {}:\n", z + 1));
+            }
+
+            for row in layer {
+                let cells: Vec<String> =
+                    row.iter().map(|d| match *d {
+                        Some(d) => format!("{:4.0}", d * 100.0),
+                        None    => String::from("   -"),
+                    }).collect();
+                text.push_str(&format!("  {}\n", cells.join("")));
+            }
+        }
+
+        text
+    }
+}
@@ -0,0 +1,189 @@
+// Automatic player, which only uses what Logic offers to the player: It
+// makes every move that can be proven to be right, and if there is none,
+// it unveils the cell that is least likely to hold a mine.
+
+use logic::{CellState, Hint, Logic};
+use topology::Pos;
+
+// Mine probabilities closer to each other than this count as the same
+const EPSILON: f64 = 1e-9;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Move {
+    // Unveil a cell that is provably safe
+    Unveil(Pos),
+    // Flag a cell that provably holds this many mines
+    Flag(Pos, usize),
+    // Unveil a cell that may hold a mine, as no cell is provably safe
+    Guess(Pos),
+}
+
+
+// Returns every move that can be proven to be right, or a single guess
+// if there is none.  Empty once the game is over, and on infinite
+// fields (which the automatic player cannot play).
+pub fn next_moves(logic: &Logic) -> Vec<Move> {
+    let topology = logic.get_topology();
+    if logic.is_game_over() || topology.is_infinite() {
+        return Vec::new();
+    }
+
+    let veiled: Vec<Pos> =
+        topology.positions()
+                .filter(|&pos| topology.is_active(pos) &&
+                               logic.get_cell_state(pos) == CellState::Veiled)
+                .collect();
+
+    let probabilities = match logic.mine_probabilities() {
+        Some(probabilities) => probabilities,
+
+        None => {
+            // Take the deductions one by one, and consider all cells
+            // equally likely to hold mines when guessing
+            return match logic.hint() {
+                Some(Hint::Safe(pos, _))    => vec![Move::Unveil(pos)],
+                Some(Hint::Mine(pos, n, _)) => vec![Move::Flag(pos, n)],
+
+                _ => {
+                    let density = density(logic, veiled.len());
+                    guess(logic, &veiled, |pos| {
+                        match logic.get_cell_state(pos) {
+                            CellState::Veiled     => density,
+                            CellState::Flagged(_) => 1.0,
+                            _                     => 0.0,
+                        }
+                    }).into_iter().collect()
+                },
+            };
+        },
+    };
+
+    let mut moves = Vec::<Move>::new();
+    for pos in topology.positions() {
+        let p = match probabilities.get(&pos) {
+            Some(&p) => p,
+            None     => continue,
+        };
+
+        // The player's flags are not trusted, so flagged cells may turn
+        // out to be safe
+        match logic.get_cell_state(pos) {
+            CellState::Veiled | CellState::Flagged(_) if p < EPSILON =>
+                moves.push(Move::Unveil(pos)),
+
+            CellState::Veiled if p > 1.0 - EPSILON =>
+                moves.push(Move::Flag(pos, 1)),
+
+            _ => ()
+        }
+    }
+
+    if moves.is_empty() {
+        // Unveiled cells are the only ones without a probability
+        moves.extend(guess(logic, &veiled, |pos| {
+            probabilities.get(&pos).cloned().unwrap_or(0.0)
+        }));
+    }
+    moves
+}
+
+// Returns the share of the @veiled_count veiled cells that hold one of
+// the mines not yet flagged (not knowing any better)
+fn density(logic: &Logic, veiled_count: usize) -> f64 {
+    let unflagged = logic.get_mine_count()
+                         .saturating_sub(logic.get_flag_count());
+
+    f64::min(unflagged as f64 / veiled_count as f64, 1.0)
+}
+
+// Picks the cell from @veiled with the lowest @probability to hold a
+// mine.  Among cells that are just as likely, picks the one whose
+// number is expected to tell the most, i.e. the one where the entropy
+// of that number is highest.
+fn guess<F: Fn(Pos) -> f64>(logic: &Logic, veiled: &[Pos], probability: F)
+    -> Option<Move>
+{
+    let lowest = veiled.iter().map(|&pos| probability(pos))
+                       .fold(1.0, f64::min);
+
+    veiled.iter().cloned()
+          .filter(|&pos| probability(pos) < lowest + EPSILON)
+          .map(|pos| (pos, number_entropy(logic, pos, &probability)))
+          .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+          .map(|(pos, _)| Move::Guess(pos))
+}
+
+// Returns the entropy (in bits) of the number that unveiling @pos would
+// show.  Treats its neighbors as if each held a mine with its
+// @probability, independently of the others (and of @pos being safe),
+// and ignores that cells may hold more than one mine.
+fn number_entropy<F: Fn(Pos) -> f64>(logic: &Logic, pos: Pos,
+                                     probability: &F)
+    -> f64
+{
+    // Probability of every number of mined neighbors
+    let mut distribution = vec![1.0];
+    for npos in logic.get_topology().neighbors(pos) {
+        let p = probability(npos);
+
+        let mut next = vec![0.0; distribution.len() + 1];
+        for (k, &q) in distribution.iter().enumerate() {
+            next[k] += q * (1.0 - p);
+            next[k + 1] += q * p;
+        }
+        distribution = next;
+    }
+
+    distribution.iter().filter(|&&q| q > 0.0)
+                .map(|&q| -q * q.log2())
+                .sum()
+}
+
+// Makes @mv like the player would.  Moves that have been overtaken by
+// earlier ones (e.g. cells that have been unveiled automatically in the
+// meantime) are skipped.
+pub fn make_move(logic: &mut Logic, mv: Move) {
+    match mv {
+        Move::Unveil(pos) | Move::Guess(pos) => {
+            if let CellState::Flagged(_) = logic.get_cell_state(pos) {
+                logic.set_flag(pos, 0);
+            }
+
+            if logic.get_cell_state(pos) == CellState::Veiled {
+                logic.pressed(pos);
+            }
+        },
+
+        Move::Flag(pos, n) => {
+            match logic.get_cell_state(pos) {
+                CellState::Flagged(count) if count == n => (),
+
+                CellState::Veiled | CellState::Flagged(_) =>
+                    logic.set_flag(pos, n),
+
+                _ => (),
+            }
+        },
+    }
+}
+
+// Plays until the game is over (or the automatic player cannot go on),
+// and returns the number of guesses made
+pub fn play(logic: &mut Logic) -> usize {
+    let mut guesses = 0;
+
+    loop {
+        let moves = next_moves(logic);
+        if moves.is_empty() {
+            return guesses;
+        }
+
+        for mv in moves {
+            if let Move::Guess(_) = mv {
+                guesses += 1;
+            }
+            make_move(logic, mv);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// One byte of storage per cell.  Finite fields store all of them in a
+// single flat array; infinite fields are split into chunks, which are
+// only allocated once something is written to them.
+
+use std::collections::HashMap;
+
+use topology::{Pos, Topology};
+
+// Edge length of the square chunks infinite fields are split into
+pub const CHUNK_SIZE: usize = 16;
+
+
+#[derive(Clone)]
+pub enum CellMap {
+    Flat {
+        // Layer by layer, row by row
+        cells: Vec<u8>,
+        dim: (usize, usize, usize),
+    },
+
+    Chunked {
+        // Indexed by chunk column and row, every chunk holds its cells
+        // row by row
+        chunks: HashMap<(usize, usize), Vec<u8>>,
+        // Value of all cells in chunks that have not been allocated
+        default: u8,
+    },
+}
+
+
+impl CellMap {
+    // Sets all cells to @value
+    pub fn new(topology: &Topology, value: u8) -> Self {
+        let dim = topology.get_dim();
+
+        if topology.is_infinite() {
+            CellMap::Chunked {
+                chunks: HashMap::new(),
+                default: value,
+            }
+        } else {
+            CellMap::Flat {
+                cells: vec![value; dim.0 * dim.1 * dim.2],
+                dim: dim,
+            }
+        }
+    }
+
+    pub fn get(&self, pos: Pos) -> u8 {
+        match *self {
+            CellMap::Flat { ref cells, dim } =>
+                cells[(pos.2 * dim.1 + pos.1) * dim.0 + pos.0],
+
+            CellMap::Chunked { ref chunks, default } => {
+                match chunks.get(&chunk_of(pos)) {
+                    Some(chunk) => chunk[offset_in_chunk(pos)],
+                    None        => default,
+                }
+            },
+        }
+    }
+
+    pub fn set(&mut self, pos: Pos, value: u8) {
+        match *self {
+            CellMap::Flat { ref mut cells, dim } =>
+                cells[(pos.2 * dim.1 + pos.1) * dim.0 + pos.0] = value,
+
+            CellMap::Chunked { ref mut chunks, default } => {
+                let chunk = chunks.entry(chunk_of(pos)).or_insert_with(|| {
+                    vec![default; CHUNK_SIZE * CHUNK_SIZE]
+                });
+                chunk[offset_in_chunk(pos)] = value;
+            },
+        }
+    }
+
+    // Sets all cells to @value
+    pub fn fill(&mut self, value: u8) {
+        match *self {
+            CellMap::Flat { ref mut cells, .. } => {
+                for cell in cells {
+                    *cell = value;
+                }
+            },
+
+            CellMap::Chunked { ref mut chunks, ref mut default } => {
+                chunks.clear();
+                *default = value;
+            },
+        }
+    }
+
+    // Returns the positions of all cells that are actually stored
+    // (all of them for finite fields, those in allocated chunks for
+    // infinite ones)
+    pub fn stored_positions(&self) -> Vec<Pos> {
+        match *self {
+            CellMap::Flat { dim, .. } => {
+                let (w, h, d) = dim;
+                (0..(w * h * d)).map(|i| (i % w, i / w % h, i / (w * h)))
+                                .collect()
+            },
+
+            CellMap::Chunked { ref chunks, .. } => {
+                chunks.keys().flat_map(|&chunk| chunk_positions(chunk))
+                             .collect()
+            },
+        }
+    }
+}
+
+
+// Returns the chunk that holds @pos
+pub fn chunk_of(pos: Pos) -> (usize, usize) {
+    (pos.0 / CHUNK_SIZE, pos.1 / CHUNK_SIZE)
+}
+
+// Returns all positions in @chunk, row by row
+pub fn chunk_positions(chunk: (usize, usize)) -> Vec<Pos> {
+    (0..(CHUNK_SIZE * CHUNK_SIZE))
+        .map(|i| (chunk.0 * CHUNK_SIZE + i % CHUNK_SIZE,
+                  chunk.1 * CHUNK_SIZE + i / CHUNK_SIZE,
+                  0))
+        .collect()
+}
+
+fn offset_in_chunk(pos: Pos) -> usize {
+    (pos.1 % CHUNK_SIZE) * CHUNK_SIZE + pos.0 % CHUNK_SIZE
+}
@@ -1,115 +1,1023 @@
 use rand;
+use std;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::rc::Rc;
 use rand::Rng;
 
+use cellmap::{self, CellMap, CHUNK_SIZE};
+use rng::SplitMix64;
+use topology::{Grid, Neighborhood, Pos, Topology};
+
 pub enum CellLabel {
-    Mine,
+    // Number of mines in this cell
+    Mine(usize),
+    // Total number of mines in the surrounding cells
     Safe(usize),
 }
 
+// Which cells are guaranteed to be free of mines on the first click
+#[derive(Clone, Copy)]
+pub enum FirstClick {
+    // The first click may hit a mine
+    Unprotected,
+    // Only the clicked cell is free
+    Cell,
+    // The clicked cell and all of its neighbors are free
+    Block,
+    // The first click unveils at least this many cells
+    Opening(usize),
+}
+
+// How mines are spread over a finite field.  A seed only yields the
+// same layout with the same generator, so none may ever be removed or
+// changed; they are told apart by their IDs.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Generator {
+    // ID 1: Draws random cells until every mine has found one that can
+    // take it.  Slows down badly when there are few such cells left.
+    Sampling,
+    // ID 2: Draws every mine from a list of the cells that can still
+    // take one, which takes linear time at any density
+    CandidateList,
+}
+
+
+// Everything but the mines themselves that spreading or relocating
+// mines changes
+#[derive(Clone, PartialEq)]
+struct Spreading {
+    unspread_mines: usize,
+    generated_chunks: HashSet<(usize, usize)>,
+    kept_free: HashSet<Pos>,
+    rng: SplitMix64,
+}
+
+// The mine layout at some point, to find out what has changed since
+// (see Game::layout_change())
+pub struct Layout {
+    // Only for finite fields (the chunks of infinite fields can simply
+    // be generated again)
+    mines: Option<CellMap>,
+    spreading: Spreading,
+}
+
+// How the mine layout has changed between two points, so that either
+// one can be restored
+pub struct LayoutChange {
+    // Every cell (of a finite field) whose number of mines has changed,
+    // with that number before and after
+    mines: Vec<(Pos, u8, u8)>,
+    spreading: [Spreading; 2],
+}
+
 
 pub struct Game {
-    dim: (usize, usize),
+    topology: Rc<Topology>,
+    dim: (usize, usize, usize),
 
-    mines: Vec<Vec<bool>>,
+    // Number of mines per cell
+    mines: CellMap,
     unspread_mines: usize,
+    // On infinite fields: number of mines per chunk
     total_mines: usize,
+    max_per_cell: usize,
+    first_click: FirstClick,
+    // Whether the layout was loaded from a board file (and thus must
+    // not be changed)
+    fixed_layout: bool,
+
+    // On infinite fields, the mines of every chunk are only placed when
+    // it is first needed.  These are the chunks that already have
+    // their mines, and the cells the first click has kept free.
+    generated_chunks: HashSet<(usize, usize)>,
+    kept_free: HashSet<Pos>,
+
+    // None for layouts loaded from a board file (unless it records the
+    // seed they were drawn with)
+    seed: Option<u64>,
+    generator: Generator,
+    rng: SplitMix64,
+}
+
+
+impl Generator {
+    pub fn from_id(id: usize) -> Option<Self> {
+        match id {
+            1 => Some(Generator::Sampling),
+            2 => Some(Generator::CandidateList),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> usize {
+        match self {
+            Generator::Sampling      => 1,
+            Generator::CandidateList => 2,
+        }
+    }
+
+    // Parses a seed as shown to the player, "<generator ID>:<seed>".  A
+    // bare seed stems from before there was a choice of generators, so
+    // it is meant for generator 1.
+    pub fn parse_seed(text: &str) -> Result<(Self, u64), String> {
+        let (generator, seed) = match text.split_once(':') {
+            Some((id, seed)) => {
+                let id = id.parse::<usize>().map_err(|e| e.to_string())?;
+                let generator = Generator::from_id(id).ok_or(
+                    format!("Unknown generator {}", id))?;
+                (generator, seed)
+            },
+
+            None => (Generator::Sampling, text),
+        };
+
+        let seed = seed.parse::<u64>().map_err(|e| e.to_string())?;
+        Ok((generator, seed))
+    }
+
+    // Formats @seed for this generator so parse_seed() accepts it
+    pub fn format_seed(self, seed: u64) -> String {
+        format!("{}:{}", self.id(), seed)
+    }
 }
 
 
 impl Game {
-    pub fn new(dim: (usize, usize), mine_count: usize) -> Self {
-        let mut mine_vec = Vec::<Vec<bool>>::new();
+    // Every cell can hold up to @max_per_cell mines.  If @seed is
+    // None, a random seed is chosen.  On infinite fields, @mine_count
+    // is the number of mines in every chunk (and @generator is ignored,
+    // as chunks are always filled from a candidate list).
+    pub fn new(topology: Topology, mine_count: usize, max_per_cell: usize,
+               seed: Option<u64>, generator: Generator,
+               first_click: FirstClick)
+        -> Self
+    {
+        let dim = topology.get_dim();
+
+        if !(1..=9).contains(&max_per_cell) {
+            panic!("Cells must hold between one and nine mines");
+        }
 
         if dim.0 < 3 || dim.1 < 3 {
             panic!("Field must be at least 3×3");
         }
 
-        if dim.0 * dim.1 - 9 < mine_count {
-            panic!("Must have at least 9 free cells");
+        if dim.2 < 1 {
+            panic!("Field must have at least one layer");
         }
 
-        for _ in 0..dim.1 {
-            let mut row = Vec::<bool>::new();
-            for _ in 0..dim.0 {
-                row.push(false);
-            }
-            mine_vec.push(row);
-        }
+        let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+        let mines = CellMap::new(&topology, 0);
 
-        Game {
+        let game = Game {
+            topology: Rc::new(topology),
             dim: dim,
 
-            mines: mine_vec,
+            mines: mines,
             unspread_mines: mine_count,
             total_mines: mine_count,
+            max_per_cell: max_per_cell,
+            first_click: first_click,
+            fixed_layout: false,
+
+            generated_chunks: HashSet::new(),
+            kept_free: HashSet::new(),
+
+            seed: Some(seed),
+            generator: generator,
+            rng: SplitMix64::new(seed),
+        };
+
+        if game.topology.is_infinite() {
+            if mine_count >= CHUNK_SIZE * CHUNK_SIZE * max_per_cell {
+                panic!("Chunks must have room for more than {} mines",
+                       mine_count);
+            }
+
+            return game;
+        }
+
+        // The first click can be anywhere, so there must be enough room
+        // for the largest set of cells that may have to be kept free
+        let required_free = match first_click {
+            FirstClick::Unprotected => 0,
+            FirstClick::Cell        => 1,
+            FirstClick::Block       => 1 + game.topology.max_neighbor_count(),
+            FirstClick::Opening(n)  => game.max_opening_size(n),
+        };
+        let required_free = std::cmp::min(required_free,
+                                          game.topology.cell_count());
+
+        if (game.topology.cell_count() - required_free) * max_per_cell <
+            mine_count
+        {
+            panic!("Must have at least {} free cells", required_free);
+        }
+
+        game
+    }
+
+    // Loads a fixed mine layout from a board file.  Its first line
+    // contains the field width and height, separated by whitespace,
+    // optionally followed by "hex" for hexagonal grids and "torus" for
+    // fields that wrap around at their edges, "max-mines=<n>" if cells
+    // can hold up to n mines, "neighbors=<orthogonal|knight|radius2>"
+    // for square grids with a neighborhood other than the 3×3 block,
+    // "layers=<n>" for three-dimensional fields, and "seed=<g>:<n>" for
+    // the seed and generator (by ID) the layout was drawn with, if any
+    // (see Generator::parse_seed()).  Then follows one
+    // line per row, with one character per cell: '*' for a mine, '2' to
+    // '9' for a cell with that many mines, '.' for a safe cell, '-' for
+    // a position that holds no cell.  Layers are separated by an empty
+    // line, and all of them must hold cells at the same positions.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                        .map_err(|e| e.to_string())?;
+
+        let mut lines = text.lines();
+
+        let header = lines.next().ok_or("Board file is empty")?;
+        let dim_vec: Vec<&str> = header.split_whitespace().collect();
+        if dim_vec.len() < 2 {
+            return Err(String::from("First line must contain the field \
+                                     width and height"));
+        }
+
+        let width = dim_vec[0].parse::<usize>().map_err(|e| e.to_string())?;
+        let height = dim_vec[1].parse::<usize>().map_err(|e| e.to_string())?;
+
+        let mut grid = Grid::Square;
+        let mut wrap = false;
+        let mut max_per_cell = 1;
+        let mut neighborhood = Neighborhood::Moore;
+        let mut depth = 1;
+        let mut seed = None;
+        let mut generator = Generator::CandidateList;
+        for option in &dim_vec[2..] {
+            if let Some(value) = option.strip_prefix("max-mines=") {
+                max_per_cell = value.parse::<usize>()
+                                    .map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            if let Some(value) = option.strip_prefix("layers=") {
+                depth = value.parse::<usize>().map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            if let Some(value) = option.strip_prefix("seed=") {
+                let (g, s) = Generator::parse_seed(value)?;
+                generator = g;
+                seed = Some(s);
+                continue;
+            }
+
+            if let Some(value) = option.strip_prefix("neighbors=") {
+                neighborhood = match value {
+                    "moore"      => Neighborhood::Moore,
+                    "orthogonal" => Neighborhood::Orthogonal,
+                    "knight"     => Neighborhood::Knight,
+                    "radius2"    => Neighborhood::Radius2,
+
+                    _ => return Err(format!("Unknown neighborhood '{}'",
+                                            value)),
+                };
+                continue;
+            }
+
+            match *option {
+                "hex"   => grid = Grid::Hex,
+                "torus" => wrap = true,
+
+                _ => return Err(format!("Unknown board option '{}'", option)),
+            }
+        }
+
+        if !(1..=9).contains(&max_per_cell) {
+            return Err(String::from("Cells must hold between one and nine \
+                                     mines"));
+        }
+
+        if width < 3 || height < 3 {
+            return Err(String::from("Field must be at least 3×3"));
+        }
+
+        if depth < 1 {
+            return Err(String::from("Field must have at least one layer"));
+        }
+
+        if depth > 1 && (grid != Grid::Square ||
+                         neighborhood != Neighborhood::Moore)
+        {
+            return Err(String::from("Only square grids with the default \
+                                     neighborhood can have multiple \
+                                     layers"));
+        }
+
+        if wrap && depth == 2 {
+            return Err(String::from("Fields that wrap around cannot have \
+                                     two layers"));
+        }
+
+        if wrap && grid == Grid::Hex && height % 2 == 1 {
+            return Err(String::from("Hexagonal fields that wrap around must \
+                                     have an even number of rows"));
+        }
+
+        if grid == Grid::Hex && neighborhood != Neighborhood::Moore {
+            return Err(String::from("Neighborhoods can only be chosen for \
+                                     square grids"));
+        }
+
+        let reach = neighborhood.reach();
+        if wrap && (width < 2 * reach + 1 || height < 2 * reach + 1) {
+            return Err(format!("Fields that wrap around must be at least \
+                                {0}×{0} for this neighborhood",
+                               2 * reach + 1));
+        }
+
+        // Layer by layer, row by row
+        let mut mine_vec = Vec::<u8>::new();
+        let mut mask = Vec::<Vec<bool>>::new();
+        let mut mine_count = 0;
+
+        for z in 0..depth {
+            if z > 0 {
+                match lines.next() {
+                    Some(line) if line.trim().is_empty() => (),
+
+                    _ => return Err(format!("Layer {} must be followed by \
+                                             an empty line", z)),
+                }
+            }
+
+            let row_name = |y: usize| {
+                if depth > 1 {
+                    format!("row {} of layer {}", y + 1, z + 1)
+                } else {
+                    format!("row {}", y + 1)
+                }
+            };
+
+            for y in 0..height {
+                let line = lines.next()
+                                .ok_or(format!("{} is missing", row_name(y)))?;
+
+                let mut row = Vec::<u8>::new();
+                let mut mask_row = Vec::<bool>::new();
+                for c in line.trim_end().chars() {
+                    match c {
+                        '*' => {
+                            row.push(1);
+                            mine_count += 1;
+                        },
+
+                        '2'..='9' => {
+                            let n = c.to_digit(10).unwrap() as usize;
+                            if n > max_per_cell {
+                                return Err(format!("Cell in {} holds more \
+                                                    than {} mines",
+                                                   row_name(y), max_per_cell));
+                            }
+
+                            row.push(n as u8);
+                            mine_count += n;
+                        },
+
+                        '.' => row.push(0),
+                        '-' => row.push(0),
+
+                        _ => return Err(format!("Invalid character '{}' in \
+                                                 {}", c, row_name(y))),
+                    }
+                    mask_row.push(c != '-');
+                }
+
+                if row.len() != width {
+                    return Err(format!("{} has {} cells instead of {}",
+                                       row_name(y), row.len(), width));
+                }
+
+                if z == 0 {
+                    mask.push(mask_row);
+                } else if mask_row != mask[y] {
+                    return Err(format!("Cells in {} differ from the first \
+                                        layer", row_name(y)));
+                }
+
+                mine_vec.extend(row);
+            }
+        }
+
+        if lines.any(|line| !line.trim().is_empty()) {
+            return Err(format!("Board has more than {} rows", height));
         }
+
+        let mut topology = Topology::new((width, height, depth), grid, wrap);
+        topology.set_neighborhood(neighborhood);
+        if mask.iter().any(|row| row.contains(&false)) {
+            topology.set_mask(mask);
+        }
+
+        let mut mines = CellMap::new(&topology, 0);
+        for (pos, n) in topology.positions().zip(mine_vec) {
+            mines.set(pos, n);
+        }
+
+        Ok(Game {
+            topology: Rc::new(topology),
+            dim: (width, height, depth),
+
+            mines: mines,
+            unspread_mines: 0,
+            total_mines: mine_count,
+            max_per_cell: max_per_cell,
+            first_click: FirstClick::Unprotected,
+            fixed_layout: true,
+
+            generated_chunks: HashSet::new(),
+            kept_free: HashSet::new(),
+
+            seed: seed,
+            generator: generator,
+            rng: SplitMix64::new(0),
+        })
     }
 
-    pub fn get_dim(&self) -> (usize, usize) {
+    // Writes the current mine layout in the format understood by
+    // Game::load()
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        if self.topology.is_infinite() {
+            return Err(String::from("Infinite fields cannot be saved"));
+        }
+
+        let mut text = format!("{} {}", self.dim.0, self.dim.1);
+        if self.topology.get_grid() == Grid::Hex {
+            text.push_str(" hex");
+        }
+        if self.topology.get_wrap() {
+            text.push_str(" torus");
+        }
+        if self.max_per_cell > 1 {
+            text.push_str(&format!(" max-mines={}", self.max_per_cell));
+        }
+        match self.topology.get_neighborhood() {
+            Neighborhood::Moore      => (),
+            Neighborhood::Orthogonal => text.push_str(" neighbors=orthogonal"),
+            Neighborhood::Knight     => text.push_str(" neighbors=knight"),
+            Neighborhood::Radius2    => text.push_str(" neighbors=radius2"),
+        }
+        if self.dim.2 > 1 {
+            text.push_str(&format!(" layers={}", self.dim.2));
+        }
+        if let Some(seed) = self.seed {
+            text.push_str(&format!(" seed={}",
+                                   self.generator.format_seed(seed)));
+        }
+        text.push('\n');
+
+        for z in 0..self.dim.2 {
+            if z > 0 {
+                text.push('\n');
+            }
+
+            for y in 0..self.dim.1 {
+                for x in 0..self.dim.0 {
+                    text.push(match self.mines.get((x, y, z)) {
+                        _ if !self.topology.is_active((x, y, z)) => '-',
+
+                        0 => '.',
+                        1 => '*',
+                        n => std::char::from_digit(n as u32, 10).unwrap(),
+                    });
+                }
+                text.push('\n');
+            }
+        }
+
+        File::create(path).and_then(|mut f| f.write_all(text.as_bytes()))
+                          .map_err(|e| e.to_string())
+    }
+
+    pub fn get_dim(&self) -> (usize, usize, usize) {
         self.dim
     }
 
-    fn has_mine(&self, pos: (i32, i32)) -> bool {
-        if pos.0 >= 0 && (pos.0 as usize) < self.dim.0 &&
-           pos.1 >= 0 && (pos.1 as usize) < self.dim.1
-        {
-            self.mines[pos.1 as usize][pos.0 as usize]
-        } else {
-            false
+    pub fn get_topology(&self) -> Rc<Topology> {
+        self.topology.clone()
+    }
+
+    // Returns the number of mines in @pos (on infinite fields, only
+    // valid once get_cell_label() has been called for @pos or one of
+    // its neighbors)
+    pub fn mines_at(&self, pos: Pos) -> usize {
+        self.mines.get(pos) as usize
+    }
+
+    pub fn get_cell_label(&mut self, pos: Pos) -> CellLabel {
+        if self.topology.is_infinite() {
+            let mut around = self.topology.neighbors(pos);
+            around.push(pos);
+
+            for apos in around {
+                let chunk = cellmap::chunk_of(apos);
+                if !self.generated_chunks.contains(&chunk) {
+                    self.generate_chunk(chunk);
+                }
+            }
+        }
+
+        match self.mines_at(pos) {
+            0 => {
+                let mine_count = self.topology.neighbors(pos).into_iter()
+                                     .map(|npos| self.mines_at(npos))
+                                     .sum();
+
+                CellLabel::Safe(mine_count)
+            },
+
+            n => CellLabel::Mine(n),
         }
     }
 
-    pub fn get_cell_label(&mut self, pos: (usize, usize)) -> CellLabel {
-        if self.mines[pos.1][pos.0] {
-            CellLabel::Mine
-        } else {
-            let mut mine_count = 0;
-            let ipos = (pos.0 as i32, pos.1 as i32);
-            for yd in -1..2 {
-                for xd in -1..2 {
-                    if self.has_mine((ipos.0 + xd, ipos.1 + yd)) {
-                        mine_count += 1;
+    // Returns the cells that must not contain mines when the first
+    // click is on @first_click
+    fn keep_free(&self, first_click: Pos) -> Vec<Pos> {
+        match self.first_click {
+            FirstClick::Unprotected => Vec::new(),
+            FirstClick::Cell        => vec![first_click],
+            FirstClick::Block       => self.ball(first_click, 1, usize::MAX),
+
+            // Keeping a ball of radius r free turns all cells in the
+            // ball of radius r - 1 into zeros, so the whole ball of
+            // radius r is then part of the opening
+            FirstClick::Opening(n)  =>
+                self.ball(first_click, usize::MAX, n),
+        }
+    }
+
+    // Returns the largest number of cells keep_free() may keep free for
+    // FirstClick::Opening(@n), wherever the first click is
+    fn max_opening_size(&self, n: usize) -> usize {
+        let topology = &self.topology;
+
+        if n >= topology.cell_count() {
+            return topology.cell_count();
+        }
+
+        if topology.is_masked() {
+            return topology.positions()
+                           .filter(|&pos| topology.is_active(pos))
+                           .map(|pos| self.ball(pos, usize::MAX, n).len())
+                           .max().unwrap_or(0);
+        }
+
+        let reach = match topology.get_grid() {
+            Grid::Square => topology.get_neighborhood().reach(),
+            Grid::Hex    => 1,
+        };
+
+        // Balls that stay at least @margin away from the borders look
+        // the same wherever they are (save for the row parity on
+        // hexagonal grids), so it is enough to try the positions up to
+        // @margin + 1 away from a border -- as long as the balls around
+        // those @margin and @margin + 1 away do not grow any further
+        let dim = topology.get_dim();
+        let center = (dim.0 / 2, dim.1 / 2, dim.2 / 2);
+        let mut margin = self.ball_with_radius(center, usize::MAX, n).1 *
+                         reach;
+        // Square grids look the same when mirrored, so one half of every
+        // axis suffices there
+        let mirrored = topology.get_grid() == Grid::Square;
+        loop {
+            let long = |len: usize| len > 2 * margin + 4;
+            let candidates = |len: usize| -> Vec<usize> {
+                let all: Vec<usize> = if long(len) {
+                    (0..margin + 2).chain(len - margin - 2..len).collect()
+                } else {
+                    (0..len).collect()
+                };
+
+                all.into_iter().filter(|&c| !mirrored || 2 * c < len)
+                   .collect()
+            };
+            let stands_in = |c: usize, len: usize| {
+                long(len) && (c == margin || c == margin + 1)
+            };
+
+            let mut max_size = 0;
+            let mut max_radius = 0;
+            for &z in &candidates(dim.2) {
+                for &y in &candidates(dim.1) {
+                    for &x in &candidates(dim.0) {
+                        let (cells, radius) =
+                            self.ball_with_radius((x, y, z), usize::MAX, n);
+
+                        max_size = std::cmp::max(max_size, cells.len());
+                        if stands_in(x, dim.0) || stands_in(y, dim.1) ||
+                           stands_in(z, dim.2)
+                        {
+                            max_radius = std::cmp::max(max_radius, radius);
+                        }
                     }
                 }
             }
 
-            CellLabel::Safe(mine_count)
+            if max_radius * reach <= margin {
+                return max_size;
+            }
+            margin = max_radius * reach;
         }
     }
 
-    pub fn spread_mines(&mut self, keep_free: (usize, usize)) {
-        let mut rng = rand::thread_rng();
+    // Returns all cells that can be reached from @center in at most
+    // @max_radius steps from one cell to a neighboring one.  Stops
+    // growing the radius once there are at least @min_size cells.
+    fn ball(&self, center: Pos, max_radius: usize, min_size: usize)
+        -> Vec<Pos>
+    {
+        self.ball_with_radius(center, max_radius, min_size).0
+    }
 
-        while self.unspread_mines > 0 {
-            let x = rng.gen_range(0, self.dim.0);
-            let y = rng.gen_range(0, self.dim.1);
+    // Like ball(), but also returns the radius the ball has grown to
+    fn ball_with_radius(&self, center: Pos, max_radius: usize,
+                        min_size: usize)
+        -> (Vec<Pos>, usize)
+    {
+        let mut cells = vec![center];
+        let mut visited = HashSet::<Pos>::new();
+        visited.insert(center);
 
-            if (keep_free.0 as i32 - x as i32).abs() <= 1 &&
-               (keep_free.1 as i32 - y as i32).abs() <= 1
-            {
-                continue;
-            }
+        let mut layer_start = 0;
+        let mut radius = 0;
 
-            if self.mines[y][x] {
-                continue;
+        while radius < max_radius && cells.len() < min_size &&
+              layer_start < cells.len()
+        {
+            let layer_end = cells.len();
+
+            for i in layer_start..layer_end {
+                for npos in self.topology.neighbors(cells[i]) {
+                    if visited.insert(npos) {
+                        cells.push(npos);
+                    }
+                }
             }
 
-            self.mines[y][x] = true;
-            self.unspread_mines -= 1;
+            layer_start = layer_end;
+            radius += 1;
         }
+
+        (cells, radius)
+    }
+
+    // The layout depends only on the seed, the generator, the field
+    // dimensions, the mine count, the first click policy, @first_click
+    // and on how often this function has been called before in this
+    // game (calling it again discards the current layout and draws a
+    // new one).  Do not change the way random numbers are drawn by
+    // either generator (or the order of the candidate cells), or old
+    // seeds will yield different layouts.
+    pub fn spread_mines(&mut self, first_click: Pos) {
+        if self.fixed_layout {
+            return;
+        }
+
+        self.clear_mines();
+
+        let kept_free: HashSet<Pos> =
+            self.keep_free(first_click).into_iter().collect();
+
+        if self.topology.is_infinite() {
+            // Mines are placed chunk by chunk later on
+            self.kept_free = kept_free;
+            self.unspread_mines = 0;
+            return;
+        }
+
+        match self.generator {
+            Generator::Sampling => {
+                sample_mines(&mut self.mines, &mut self.rng, &self.topology,
+                             &kept_free, self.unspread_mines,
+                             self.max_per_cell);
+            },
+
+            Generator::CandidateList => {
+                let candidates: Vec<Pos> =
+                    self.topology.positions()
+                        .filter(|pos| self.topology.is_active(*pos) &&
+                                      !kept_free.contains(pos))
+                        .collect();
+
+                place_mines(&mut self.mines, &mut self.rng, candidates,
+                            self.unspread_mines, self.max_per_cell);
+            },
+        }
+        self.unspread_mines = 0;
+    }
+
+    // Places the mines of @chunk (on an infinite field).  They depend
+    // only on the seed, the chunk's position, the number of mines per
+    // chunk and on which cells the first click has kept free.
+    fn generate_chunk(&mut self, chunk: (usize, usize)) {
+        let mut rng = SplitMix64::new(self.seed.unwrap() ^
+                                      ((chunk.0 as u64) << 32 |
+                                       chunk.1 as u64));
+        // Decorrelate neighboring chunks
+        let mut rng = SplitMix64::new(rng.next_u64());
+
+        let candidates: Vec<Pos> =
+            cellmap::chunk_positions(chunk).into_iter()
+                .filter(|pos| !self.kept_free.contains(pos))
+                .collect();
+
+        // Chunks touched by the first click may not have enough room
+        let count = std::cmp::min(self.total_mines,
+                                  candidates.len() * self.max_per_cell);
+
+        place_mines(&mut self.mines, &mut rng, candidates, count,
+                    self.max_per_cell);
+        self.generated_chunks.insert(chunk);
     }
 
     pub fn get_mine_count(&self) -> usize {
         self.total_mines
     }
 
-    pub fn new_game(&mut self) {
-        for y in 0..self.dim.1 {
-            for x in 0..self.dim.0 {
-                self.mines[y][x] = false;
+    pub fn get_max_per_cell(&self) -> usize {
+        self.max_per_cell
+    }
+
+    // Returns the number of cells that contain at least one mine
+    pub fn get_mined_cell_count(&self) -> usize {
+        self.mine_positions().len()
+    }
+
+    // Returns all cells that contain mines (on infinite fields, only
+    // those in chunks whose mines have been placed already)
+    pub fn mine_positions(&self) -> Vec<Pos> {
+        self.mines.stored_positions().into_iter()
+                  .filter(|&pos| self.mines.get(pos) > 0)
+                  .collect()
+    }
+
+    pub fn get_seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn get_generator(&self) -> Generator {
+        self.generator
+    }
+
+    // Sets every cell in @fixed to the given number of mines, and then
+    // adds or removes mines in the cells in @free (at random) until the
+    // total mine count is right again.  All other cells keep their
+    // mines.  @free must have room for the mines that remain.
+    pub fn relocate_mines(&mut self, fixed: &[(Pos, usize)], free: &[Pos]) {
+        for &(pos, n) in fixed {
+            self.mines.set(pos, n as u8);
+        }
+
+        let placed: usize = self.mine_positions().into_iter()
+                                .map(|pos| self.mines_at(pos))
+                                .sum();
+
+        if placed > self.total_mines {
+            // One entry per mine, so that every mine is equally likely
+            // to be removed
+            let mut mined: Vec<Pos> =
+                free.iter()
+                    .flat_map(|&pos| {
+                        std::iter::repeat_n(pos, self.mines_at(pos))
+                    })
+                    .collect();
+
+            for _ in 0..(placed - self.total_mines) {
+                let i = self.rng.gen_range(0, mined.len());
+                let pos = mined.swap_remove(i);
+                let n = self.mines.get(pos) - 1;
+                self.mines.set(pos, n);
             }
+        } else {
+            let candidates: Vec<Pos> =
+                free.iter()
+                    .filter(|&&pos| self.mines_at(pos) < self.max_per_cell)
+                    .cloned()
+                    .collect();
+
+            place_mines(&mut self.mines, &mut self.rng, candidates,
+                        self.total_mines - placed, self.max_per_cell);
         }
+    }
+
+    fn clear_mines(&mut self) {
+        self.mines.fill(0);
+        self.generated_chunks.clear();
+        self.kept_free.clear();
 
         self.unspread_mines = self.total_mines;
     }
+
+    fn spreading(&self) -> Spreading {
+        Spreading {
+            unspread_mines: self.unspread_mines,
+            generated_chunks: self.generated_chunks.clone(),
+            kept_free: self.kept_free.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout {
+            mines: match self.topology.is_infinite() {
+                true  => None,
+                false => Some(self.mines.clone()),
+            },
+            spreading: self.spreading(),
+        }
+    }
+
+    // Returns how the layout has changed since @before was taken (None
+    // if it has not)
+    pub fn layout_change(&self, before: Layout) -> Option<LayoutChange> {
+        let mines: Vec<(Pos, u8, u8)> = match before.mines {
+            Some(ref mines) =>
+                self.mines.stored_positions().into_iter()
+                    .filter(|&pos| mines.get(pos) != self.mines.get(pos))
+                    .map(|pos| (pos, mines.get(pos), self.mines.get(pos)))
+                    .collect(),
+
+            None => Vec::new(),
+        };
+
+        let spreading = self.spreading();
+        if mines.is_empty() && spreading == before.spreading {
+            return None;
+        }
+
+        Some(LayoutChange {
+            mines: mines,
+            spreading: [before.spreading, spreading],
+        })
+    }
+
+    // Puts the layout back into the state it had before @change (or,
+    // for @after, after it)
+    pub fn restore_layout(&mut self, change: &LayoutChange, after: bool) {
+        let spreading = change.spreading[after as usize].clone();
+
+        for &(pos, before_count, after_count) in &change.mines {
+            self.mines.set(pos, if after { after_count } else { before_count });
+        }
+
+        self.unspread_mines = spreading.unspread_mines;
+        self.kept_free = spreading.kept_free;
+        self.rng = spreading.rng;
+
+        if self.topology.is_infinite() {
+            // Chunks only depend on the seed and the cells kept free
+            self.mines.fill(0);
+            self.generated_chunks.clear();
+            for chunk in spreading.generated_chunks {
+                self.generate_chunk(chunk);
+            }
+        }
+    }
+
+    // Starts a new game with a new random seed (or with the same
+    // layout again if it was loaded from a board file)
+    pub fn new_game(&mut self) {
+        if self.fixed_layout {
+            return;
+        }
+
+        self.clear_mines();
+
+        let seed = rand::thread_rng().gen();
+        self.seed = Some(seed);
+        self.rng = SplitMix64::new(seed);
+    }
+}
+
+
+// Puts @count mines into the active cells of @topology that are not in
+// @kept_free (at most @max_per_cell into each), by drawing random cells
+// until enough of them could take a mine.  This is Generator::Sampling,
+// which must keep drawing exactly the same numbers.
+fn sample_mines(mines: &mut CellMap, rng: &mut SplitMix64,
+                topology: &Topology, kept_free: &HashSet<Pos>,
+                count: usize, max_per_cell: usize)
+{
+    let dim = topology.get_dim();
+    let mut unspread = count;
+
+    while unspread > 0 {
+        let x = rng.gen_range(0, dim.0);
+        let y = rng.gen_range(0, dim.1);
+        // Only drawn for 3D fields, so flat layouts stay the same
+        let z = if dim.2 > 1 {
+            rng.gen_range(0, dim.2)
+        } else {
+            0
+        };
+
+        if kept_free.contains(&(x, y, z)) || !topology.is_active((x, y, z)) {
+            continue;
+        }
+
+        let n = mines.get((x, y, z));
+        if n as usize == max_per_cell {
+            continue;
+        }
+
+        mines.set((x, y, z), n + 1);
+        unspread -= 1;
+    }
+}
+
+// Puts @count mines into the cells given by @candidates (at most
+// @max_per_cell into each).  Every mine goes into one of the cells that
+// can still take one, chosen uniformly; full cells are then swapped
+// out, so this takes linear time regardless of the mine density.
+fn place_mines(mines: &mut CellMap, rng: &mut SplitMix64,
+               mut candidates: Vec<Pos>, count: usize, max_per_cell: usize)
+{
+    for _ in 0..count {
+        let i = rng.gen_range(0, candidates.len());
+        let pos = candidates[i];
+
+        let n = mines.get(pos) + 1;
+        mines.set(pos, n);
+
+        if n as usize == max_per_cell {
+            candidates.swap_remove(i);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spreads the mines for a fixed seed and returns the layout, one
+    // string per row
+    fn layout(generator: Generator) -> Vec<String> {
+        let topology = Topology::new((16, 8, 1), Grid::Square, false);
+        let mut game = Game::new(topology, 20, 1, Some(12345), generator,
+                                 FirstClick::Block);
+        game.spread_mines((3, 4, 0));
+
+        (0..8).map(|y| {
+            (0..16).map(|x| if game.mines.get((x, y, 0)) > 0 { '*' }
+                            else { '.' })
+                   .collect()
+        }).collect()
+    }
+
+    // Seeds must keep yielding the same layouts, or players cannot play
+    // the games they have been given again
+    #[test]
+    fn seeds_keep_their_layouts() {
+        assert_eq!(layout(Generator::Sampling), [
+            ".............*..",
+            ".....*..........",
+            "....*.*.*....*..",
+            "*..........**...",
+            ".*....*.........",
+            "*.........*.*...",
+            "*....*.....**...",
+            "..*....*........",
+        ]);
+
+        assert_eq!(layout(Generator::CandidateList), [
+            "........*.......",
+            ".......**.....*.",
+            "..*........**...",
+            "......*...*.....",
+            ".*..........*...",
+            ".....*.*.**.....",
+            ".......*.....*..",
+            "...*...**.......",
+        ]);
+    }
+
+    #[test]
+    fn seeds_name_their_generator() {
+        assert!(Generator::parse_seed("12345") ==
+                Ok((Generator::Sampling, 12345)));
+        assert!(Generator::parse_seed("2:12345") ==
+                Ok((Generator::CandidateList, 12345)));
+        assert!(Generator::parse_seed("3:12345").is_err());
+
+        for &generator in &[Generator::Sampling, Generator::CandidateList] {
+            let text = generator.format_seed(12345);
+            assert!(Generator::parse_seed(&text) == Ok((generator, 12345)));
+        }
+    }
 }
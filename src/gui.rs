@@ -1,11 +1,208 @@
-use gdk_pixbuf::Pixbuf;
+use cairo;
+use gdk;
+use gdk::prelude::*;
+use gdk_pixbuf::{Pixbuf, PixbufExt};
 use gtk;
 use gtk::prelude::*;
 use std;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-use logic::{CellState, Logic};
+use autoplay;
+use logic::{CellState, Hint, Logic, Reason};
+use topology::{Grid, Pos};
+
+
+// Space between the layers of a 3D field
+const LAYER_SPACING: i32 = 10;
+
+// Number of cells the arrow keys move the view by on infinite fields
+const PAN_STEP: usize = 4;
+
+
+fn seed_label(logic: &Logic) -> String {
+    let seed = match logic.get_seed() {
+        // Chunks of infinite fields are always placed the same way
+        Some(seed) if logic.get_topology().is_infinite() =>
+            format!("Seed: {}", seed),
+        Some(seed) =>
+            format!("Seed: {}", logic.get_generator().format_seed(seed)),
+        None       => String::from("Layout loaded from file"),
+    };
+
+    match logic.get_target_met() {
+        Some(true)  => format!("{} (matches the target difficulty)", seed),
+        Some(false) => format!("{} (no layout of the target difficulty \
+                                found)", seed),
+        None        => seed,
+    }
+}
+
+// Flag count, or on infinite fields (where the mine count is unknown)
+// the score
+fn status_label(logic: &Logic) -> String {
+    if logic.get_topology().is_infinite() {
+        format!("Cells cleared: {}", logic.get_unveiled_count())
+    } else {
+        format!("Mines flagged: {} / {}", logic.get_flag_count(),
+                logic.get_mine_count())
+    }
+}
+
+// Difficulty metrics of the layout, only shown once the game is over
+fn analysis_label(logic: &Logic) -> String {
+    if !logic.is_game_over() {
+        return String::new();
+    }
+
+    match logic.analyze() {
+        Some(analysis) => analysis.summary(),
+        None           => String::new(),
+    }
+}
+
+fn hint_label(hint: &Hint) -> String {
+    match *hint {
+        Hint::Safe(_, _) =>
+            String::from("Hint: The highlighted veiled cell is safe, as the \
+                          highlighted numbers show"),
+
+        Hint::Mine(_, 1, _) =>
+            String::from("Hint: The highlighted veiled cell holds a mine, as \
+                          the highlighted numbers show"),
+
+        Hint::Mine(_, n, _) =>
+            format!("Hint: The highlighted veiled cell holds {} mines, as \
+                     the highlighted numbers show", n),
+
+        Hint::Guess =>
+            String::from("Hint: No cell can be proven to be safe, so you \
+                          have to guess"),
+    }
+}
+
+// Explains why @pos has been unveiled or flagged automatically (None if
+// the player did that)
+fn reason_label(logic: &Logic, pos: Pos) -> Option<String> {
+    let reason = logic.get_reason(pos)?;
+
+    let action = match logic.get_cell_state(pos) {
+        CellState::Flagged(_) => "Flagged",
+        _                     => "Unveiled",
+    };
+
+    // Positions are shown starting at 1, and with the layer only on
+    // 3D fields
+    let number = |npos: Pos| {
+        let at = match logic.get_dim().2 {
+            1 => format!("({}, {})", npos.0 + 1, npos.1 + 1),
+            _ => format!("({}, {}, {})", npos.0 + 1, npos.1 + 1, npos.2 + 1),
+        };
+
+        match logic.get_cell_state(npos) {
+            CellState::Safe(n) => format!("the {} at {}", n, at),
+            _                  => format!("the cell at {}", at),
+        }
+    };
+
+    let why = match reason {
+        Reason::AllSafe(npos) => {
+            if logic.get_cell_state(npos) == CellState::Safe(0) {
+                format!("{} has no mines around it", number(npos))
+            } else {
+                format!("{} has all of its mines flagged already",
+                        number(npos))
+            }
+        },
+
+        Reason::AllMines(npos) =>
+            format!("{} has just as many veiled cells around it as mines \
+                     left", number(npos)),
+
+        Reason::Pair(apos, bpos) =>
+            format!("follows from {} together with {}",
+                    number(apos), number(bpos)),
+
+        Reason::MineCount =>
+            String::from("follows from the total number of mines"),
+
+        Reason::Contradiction =>
+            String::from("anything else contradicts the numbers around it"),
+    };
+
+    Some(format!("{} automatically: {}", action, why))
+}
+
+// Returns a copy of @base with @text drawn on it, either in the center
+// or (if @centered is false) in the bottom right corner
+fn label_pixbuf(base: &Pixbuf, text: &str, centered: bool) -> Pixbuf {
+    let w = base.get_width();
+    let h = base.get_height();
+
+    let surface =
+        cairo::ImageSurface::create(cairo::Format::ARgb32, w, h).unwrap();
+
+    {
+        let cr = cairo::Context::new(&surface);
+        cr.set_source_pixbuf(base, 0.0, 0.0);
+        cr.paint();
+
+        cr.select_font_face("Sans", cairo::FontSlant::Normal,
+                            cairo::FontWeight::Bold);
+        cr.set_font_size(h as f64 * if centered { 0.6 } else { 0.45 });
+
+        let ext = cr.text_extents(text);
+        let (x, y) = if centered {
+            ((w as f64 - ext.width) / 2.0 - ext.x_bearing,
+             (h as f64 - ext.height) / 2.0 - ext.y_bearing)
+        } else {
+            (w as f64 * 0.95 - ext.width - ext.x_bearing,
+             h as f64 * 0.95 - ext.height - ext.y_bearing)
+        };
+
+        // Black text with a white outline, so it is readable on
+        // anything
+        cr.move_to(x, y);
+        cr.text_path(text);
+        cr.set_source_rgb(1.0, 1.0, 1.0);
+        cr.set_line_width(h as f64 / 16.0);
+        cr.stroke_preserve();
+        cr.set_source_rgb(0.0, 0.0, 0.0);
+        cr.fill();
+    }
+
+    gdk::pixbuf_get_from_surface(&surface, 0, 0, w, h).unwrap()
+}
+
+// Asks the user for a file name and saves the mine layout there
+fn save_board(wnd: &gtk::Window, logic: &RefCell<Logic>) {
+    let dialog = gtk::FileChooserDialog::with_buttons(
+        Some("Save board"), Some(wnd), gtk::FileChooserAction::Save,
+        &[("_Cancel", gtk::ResponseType::Cancel),
+          ("_Save", gtk::ResponseType::Accept)]);
+    dialog.set_do_overwrite_confirmation(true);
+    dialog.set_current_name("board.txt");
+
+    let response = dialog.run();
+    let filename = dialog.get_filename();
+    dialog.destroy();
+
+    if response != gtk::ResponseType::Accept.into() {
+        return;
+    }
+
+    if let Some(path) = filename {
+        if let Err(e) = logic.borrow().save_board(&path) {
+            let msg = gtk::MessageDialog::new(
+                Some(wnd), gtk::DialogFlags::MODAL, gtk::MessageType::Error,
+                gtk::ButtonsType::Close,
+                &format!("Failed to save board: {}", e));
+            msg.run();
+            msg.destroy();
+        }
+    }
+}
 
 
 struct Cell {
@@ -17,31 +214,71 @@ struct Cell {
 pub struct GUI {
     wnd: gtk::Window,
     mines_remaining: gtk::Label,
-    buttons: Vec<Vec<Cell>>,
+    seed: gtk::Label,
+    analysis: gtk::Label,
+    // Indexed by layer, row and column (relative to the view)
+    buttons: Vec<Vec<Vec<Cell>>>,
+    // Position of the top left cell shown, and number of cells shown;
+    // only infinite fields are not shown in full
+    view: (usize, usize),
+    view_dim: (usize, usize, usize),
     logic: Option<Rc<RefCell<Logic>>>,
 
     pxb_veiled: Pixbuf,
     pxb_flagged: Pixbuf,
     pxb_mine: Pixbuf,
     pxb_safe: Vec<Pixbuf>,
-
-    total_mine_count: usize,
+    // Images for states that have no image file of their own (such as
+    // flags standing for multiple mines, or numbers above 8 with larger
+    // neighborhoods), generated when needed
+    pxb_generated: HashMap<CellState, Pixbuf>,
+    // Veiled cells showing their mine probability (in percent)
+    pxb_probability: HashMap<usize, Pixbuf>,
+
+    // Whether veiled cells show their mine probability
+    probabilities_shown: bool,
+    // Cells highlighted for the hint currently shown (if any)
+    hinted: Option<Vec<Pos>>,
+    // Cell under the mouse pointer (relative to the view), which number
+    // keys flag
+    hovered: Option<Pos>,
+
+    // Whether the automatic player should play, whether its timer is
+    // running, and the time between two of its moves (in ms)
+    autoplaying: bool,
+    autoplay_timer: bool,
+    autoplay_delay: u32,
 }
 
 
 impl GUI {
-    pub fn new(logic: Logic) -> Self {
+    // Infinite fields are shown through a window of @view_size cells,
+    // starting in their center.  The automatic player waits
+    // @autoplay_delay ms between two moves.
+    pub fn new(logic: Logic, view_size: (usize, usize), autoplay_delay: u32)
+        -> Self
+    {
         gtk::init().unwrap();
 
         let wnd = gtk::Window::new(gtk::WindowType::Toplevel);
         wnd.set_title("EasyMiner");
         wnd.set_default_size(780, 420);
 
-        let total_mine_count = logic.get_mine_count();
+        let dim = logic.get_dim();
+        let (view, view_dim) = if logic.get_topology().is_infinite() {
+            ((dim.0 / 2 - view_size.0 / 2, dim.1 / 2 - view_size.1 / 2),
+             (view_size.0, view_size.1, 1))
+        } else {
+            ((0, 0), dim)
+        };
 
         let mines_remaining =
-            gtk::Label::new(Some(format!("Mines flagged: 0 / {}",
-                                         total_mine_count).as_ref()));
+            gtk::Label::new(Some(status_label(&logic).as_ref()));
+
+        let seed = gtk::Label::new(Some(seed_label(&logic).as_ref()));
+        seed.set_selectable(true);
+
+        let analysis = gtk::Label::new(None);
 
         // Doesn't matter anyway, as the resize handler is called
         // basically immediately after .build().
@@ -56,7 +293,11 @@ impl GUI {
         GUI {
             wnd: wnd,
             buttons: Vec::new(),
+            view: view,
+            view_dim: view_dim,
             mines_remaining: mines_remaining,
+            seed: seed,
+            analysis: analysis,
             logic: Some(Rc::new(RefCell::new(logic))),
 
             pxb_veiled: Pixbuf::new_from_file_at_size("images/veiled.png",
@@ -66,70 +307,156 @@ impl GUI {
             pxb_mine: Pixbuf::new_from_file_at_size("images/mine.png",
                                                     fs, fs).unwrap(),
             pxb_safe: safe_vec,
+            pxb_generated: HashMap::new(),
+            pxb_probability: HashMap::new(),
 
-            total_mine_count: total_mine_count,
+            probabilities_shown: false,
+            hinted: None,
+            hovered: None,
+
+            autoplaying: false,
+            autoplay_timer: false,
+            autoplay_delay: autoplay_delay,
         }
     }
 
     pub fn main_loop(mut self) {
-        let grid = gtk::Grid::new();
+        // Layers of 3D fields are shown side by side
+        let layers = gtk::Box::new(gtk::Orientation::Horizontal,
+                                   LAYER_SPACING);
 
         let logic = self.logic.unwrap();
         self.logic = None;
 
-        let dim = logic.borrow().get_dim();
+        let dim = self.view_dim;
+        let view = self.view;
+        let topology = logic.borrow().get_topology();
+        let grid_type = topology.get_grid();
 
         let this = Rc::new(RefCell::new(self));
 
+        for z in 0..dim.2 {
+            let grid = gtk::Grid::new();
+            let mut btn_layer = Vec::<Vec<Cell>>::new();
 
-        for y in 0..dim.1 {
-            let mut btn_row = Vec::<Cell>::new();
-
-            for x in 0..dim.0 {
-                let btn =
-                    gtk::Image::new_from_pixbuf(&this.borrow().pxb_veiled);
+            if grid_type == Grid::Hex {
+                // Every cell spans two columns, so odd rows can be
+                // shifted by half a cell
+                grid.set_column_homogeneous(true);
+            }
 
-                let event = gtk::EventBox::new();
-                event.add(&btn);
+            if topology.is_masked() {
+                // Rows and columns that contain no cells at all must keep
+                // their size nonetheless
+                grid.set_column_homogeneous(true);
+                grid.set_row_homogeneous(true);
+            }
 
-                let cloned_logic = logic.clone();
-                let cloned_this = this.clone();
-                event.connect_button_press_event(move |_, mb| {
-                    let mut cbl = cloned_logic.borrow_mut();
-                    let cbs = &mut *cloned_this.borrow_mut();
+            for y in 0..dim.1 {
+                let mut btn_row = Vec::<Cell>::new();
 
-                    match mb.get_button() {
-                        1 => cbl.pressed((x, y)),
-                        3 => cbl.toggle_flag((x, y)),
+                for x in 0..dim.0 {
+                    let btn =
+                        gtk::Image::new_from_pixbuf(&this.borrow().pxb_veiled);
 
-                        _ => ()
-                    };
+                    if !topology.is_active((view.0 + x, view.1 + y, z)) {
+                        // Never shown
+                        btn_row.push(Cell {
+                            button: btn,
+                            state: CellState::Veiled,
+                        });
+                        continue;
+                    }
 
-                    for update in cbl.get_ui_updates() {
-                        cbs.set_cell_state(update.pos, update.state);
+                    let event = gtk::EventBox::new();
+                    event.add(&btn);
+
+                    let cloned_logic = logic.clone();
+                    let cloned_this = this.clone();
+                    event.connect_button_press_event(move |_, mb| {
+                        let mut cbl = cloned_logic.borrow_mut();
+                        let cbs = &mut *cloned_this.borrow_mut();
+                        let pos = (cbs.view.0 + x, cbs.view.1 + y, z);
+
+                        cbs.clear_hint();
+                        match mb.get_button() {
+                            1 => cbl.pressed(pos),
+                            3 => cbl.toggle_flag(pos),
+
+                            _ => ()
+                        };
+
+                        cbs.update(&mut cbl);
+
+                        Inhibit(false)
+                    });
+
+                    let cloned_this = this.clone();
+                    event.connect_enter_notify_event(move |_, _| {
+                        cloned_this.borrow_mut().hovered = Some((x, y, z));
+                        Inhibit(false)
+                    });
+
+                    let cloned_this = this.clone();
+                    event.connect_leave_notify_event(move |_, _| {
+                        cloned_this.borrow_mut().hovered = None;
+                        Inhibit(false)
+                    });
+
+                    if dim.2 > 1 {
+                        // Neighbors in other layers are hard to make out,
+                        // so highlight all of them while hovering
+                        let cloned_this = this.clone();
+                        let cloned_topology = topology.clone();
+                        event.connect_enter_notify_event(move |_, _| {
+                            let cbs = cloned_this.borrow();
+                            for npos in cloned_topology.neighbors((x, y, z)) {
+                                cbs.cell(npos).button.drag_highlight();
+                            }
+                            Inhibit(false)
+                        });
+
+                        let cloned_this = this.clone();
+                        let cloned_topology = topology.clone();
+                        event.connect_leave_notify_event(move |_, _| {
+                            let cbs = cloned_this.borrow();
+                            for npos in cloned_topology.neighbors((x, y, z)) {
+                                cbs.cell(npos).button.drag_unhighlight();
+                            }
+                            Inhibit(false)
+                        });
                     }
-                    cbs.set_flag_count(cbl.get_flag_count());
 
-                    Inhibit(false)
-                });
+                    match grid_type {
+                        Grid::Square =>
+                            grid.attach(&event, x as i32, y as i32, 1, 1),
+
+                        Grid::Hex =>
+                            grid.attach(&event, (2 * x + y % 2) as i32,
+                                        y as i32, 2, 1),
+                    }
 
-                grid.attach(&event, x as i32, y as i32, 1, 1);
+                    btn_row.push(Cell {
+                        button: btn,
+                        state: CellState::Veiled,
+                    });
+                }
 
-                btn_row.push(Cell {
-                    button: btn,
-                    state: CellState::Veiled,
-                });
+                btn_layer.push(btn_row);
             }
 
-            this.borrow_mut().buttons.push(btn_row);
+            this.borrow_mut().buttons.push(btn_layer);
+            layers.add(&grid);
         }
 
-        grid.set_halign(gtk::Align::Center);
-        grid.set_valign(gtk::Align::Center);
+        layers.set_halign(gtk::Align::Center);
+        layers.set_valign(gtk::Align::Center);
 
         let window_box = gtk::Box::new(gtk::Orientation::Vertical, 5);
-        window_box.add(&grid);
+        window_box.add(&layers);
         window_box.add(&this.borrow().mines_remaining);
+        window_box.add(&this.borrow().seed);
+        window_box.add(&this.borrow().analysis);
 
         this.borrow_mut().wnd.add(&window_box);
         this.borrow_mut().wnd.show_all();
@@ -142,23 +469,100 @@ impl GUI {
         {
             let cloned_logic = logic.clone();
             let cloned_this = this.clone();
-            this.borrow_mut().wnd.connect_key_press_event(move |_, key| {
+            this.borrow_mut().wnd.connect_key_press_event(move |wnd, key| {
+                if key.get_keyval() == 115 /* 's' */ {
+                    // Do not keep anything borrowed while the dialog runs
+                    if cloned_logic.borrow().is_game_over() {
+                        save_board(wnd, &cloned_logic);
+                    }
+                    return Inhibit(false);
+                }
+
                 let mut cbl = cloned_logic.borrow_mut();
                 let cbs = &mut *cloned_this.borrow_mut();
 
+                cbs.clear_hint();
+
                 if key.get_keyval() == 110 /* 'n' */ {
                     cbl.new_game();
 
-                    let dim = cbl.get_dim();
-                    for y in 0..dim.1 {
-                        for x in 0..dim.0 {
-                            cbs.set_cell_state((x, y), CellState::Veiled);
+                    for pos in cbs.view_positions() {
+                        cbs.set_cell_state(pos, CellState::Veiled);
+                        cbs.show_reason(pos, &cbl);
+                    }
+
+                    cbs.mines_remaining.set_label(&status_label(&cbl));
+                    cbs.seed.set_label(&seed_label(&cbl));
+                    cbs.analysis.set_label("");
+                }
+
+                if cbl.get_topology().is_infinite() {
+                    let view = cbs.view;
+                    cbs.view = match key.get_keyval() {
+                        65361 /* Left */  => (view.0 - PAN_STEP, view.1),
+                        65362 /* Up */    => (view.0, view.1 - PAN_STEP),
+                        65363 /* Right */ => (view.0 + PAN_STEP, view.1),
+                        65364 /* Down */  => (view.0, view.1 + PAN_STEP),
+
+                        _ => view,
+                    };
+
+                    if cbs.view != view {
+                        for pos in cbs.view_positions() {
+                            cbs.set_cell_state(pos, cbl.get_cell_state(pos));
+                            cbs.show_reason(pos, &cbl);
                         }
                     }
+                }
+
+                if key.get_state().contains(gdk::ModifierType::CONTROL_MASK) {
+                    let done = match key.get_keyval() {
+                        122 /* 'z' */ => cbl.undo(),
+                        90  /* 'Z' */ => cbl.redo(),
+
+                        _ => false,
+                    };
+
+                    if done {
+                        cbs.update(&mut cbl);
+                    }
+                }
+
+                match (key.get_keyval(), cbs.hovered) {
+                    (k @ 48..=57 /* '0'..'9' */, Some((x, y, z))) => {
+                        let pos = (cbs.view.0 + x, cbs.view.1 + y, z);
+                        cbl.set_flag(pos, (k - 48) as usize);
+                        cbs.update(&mut cbl);
+                    },
+
+                    _ => ()
+                }
+
+                if key.get_keyval() == 104 /* 'h' */ {
+                    cbs.show_hint(&cbl);
+                }
+
+                if key.get_keyval() == 97 /* 'a' */ {
+                    cbs.autoplaying = !cbs.autoplaying;
+
+                    if cbs.autoplaying && !cbs.autoplay_timer {
+                        cbs.autoplay_timer = true;
+
+                        let cloned_logic = cloned_logic.clone();
+                        let cloned_this = cloned_this.clone();
+                        gtk::timeout_add(cbs.autoplay_delay, move || {
+                            let mut cbl = cloned_logic.borrow_mut();
+                            let cbs = &mut *cloned_this.borrow_mut();
+                            Continue(cbs.autoplay_step(&mut cbl))
+                        });
+                    }
+                }
 
-                    cbs.mines_remaining.set_label(
-                        &format!("Mines flagged: 0 / {}",
-                                 cbs.total_mine_count));
+                if key.get_keyval() == 112 /* 'p' */ {
+                    cbs.probabilities_shown = !cbs.probabilities_shown;
+                    cbs.show_probabilities(&cbl);
+                } else if cbs.probabilities_shown {
+                    cbs.show_probabilities(&cbl);
                 }
 
                 Inhibit(false)
@@ -170,7 +574,7 @@ impl GUI {
             let cloned_this = this.clone();
             this.borrow_mut().wnd.connect_configure_event(move |_, evt| {
                 let cbs = &mut *cloned_this.borrow_mut();
-                let dim = cloned_logic.borrow().get_dim();
+                let dim = cbs.view_dim;
                 let mut wnd_size = evt.get_size();
 
                 // FIXME: This leaves some space so the user can make the window
@@ -179,10 +583,21 @@ impl GUI {
                 // window, it cannot be shrunk at all.  It would be nice to fix
                 // this and drop this spacing here (and in the resize handler).
                 wnd_size.0 -= 30;
-                wnd_size.1 -= 25 +
-                    cbs.mines_remaining.get_allocated_height() as u32;
-
-                let fs = std::cmp::min((wnd_size.0 as i32) / (dim.0 as i32),
+                wnd_size.1 -= 30 +
+                    cbs.mines_remaining.get_allocated_height() as u32 +
+                    cbs.seed.get_allocated_height() as u32 +
+                    cbs.analysis.get_allocated_height() as u32;
+
+                let layer_width = ((wnd_size.0 as i32) -
+                                   LAYER_SPACING * (dim.2 as i32 - 1)) /
+                                  (dim.2 as i32);
+                let width = match grid_type {
+                    Grid::Square => layer_width / (dim.0 as i32),
+
+                    // Rows are offset by half a cell
+                    Grid::Hex => layer_width * 2 / (dim.0 as i32 * 2 + 1),
+                };
+                let fs = std::cmp::min(width,
                                        (wnd_size.1 as i32) / (dim.1 as i32));
 
                 cbs.pxb_veiled =
@@ -198,12 +613,16 @@ impl GUI {
                     cbs.pxb_safe[i] = Pixbuf::new_from_file_at_size(
                         format!("images/safe-{}.png", i), fs, fs).unwrap();
                 }
+                cbs.pxb_generated.clear();
+                cbs.pxb_probability.clear();
 
-                for y in 0..dim.1 {
-                    for x in 0..dim.0 {
-                        let state = cbs.buttons[y][x].state;
-                        cbs.set_cell_state((x, y), state);
-                    }
+                for pos in cbs.view_positions() {
+                    let state = cbs.cell(pos).state;
+                    cbs.set_cell_state(pos, state);
+                }
+
+                if cbs.probabilities_shown {
+                    cbs.show_probabilities(&cloned_logic.borrow());
                 }
 
                 false
@@ -213,32 +632,190 @@ impl GUI {
         gtk::main();
     }
 
-    pub fn set_cell_state(&mut self, pos: (usize, usize), state: CellState) {
-        let btn = &mut self.buttons[pos.1][pos.0];
+    // Shows everything that has changed in @logic
+    fn update(&mut self, logic: &mut Logic) {
+        for update in logic.get_ui_updates() {
+            self.set_cell_state(update.pos, update.state);
+            self.show_reason(update.pos, logic);
+        }
+        self.mines_remaining.set_label(&status_label(logic));
+        self.seed.set_label(&seed_label(logic));
+        self.analysis.set_label(&analysis_label(logic));
+        if self.probabilities_shown {
+            self.show_probabilities(logic);
+        }
+    }
+
+    // Lets the automatic player make a move, unless it has been stopped
+    // or has nothing left to do.  Returns whether it should go on.
+    fn autoplay_step(&mut self, logic: &mut Logic) -> bool {
+        let mv = match autoplay::next_moves(logic).first() {
+            Some(&mv) if self.autoplaying => mv,
+
+            _ => {
+                self.autoplaying = false;
+                self.autoplay_timer = false;
+                return false;
+            },
+        };
+
+        self.clear_hint();
+        autoplay::make_move(logic, mv);
+        self.update(logic);
+        true
+    }
+
+    // @pos must be within the view
+    fn cell(&self, pos: Pos) -> &Cell {
+        &self.buttons[pos.2][pos.1 - self.view.1][pos.0 - self.view.0]
+    }
+
+    // Returns all positions on the field that are currently shown
+    fn view_positions(&self) -> Vec<Pos> {
+        let (w, h, d) = self.view_dim;
+        (0..(w * h * d)).map(|i| (self.view.0 + i % w,
+                                  self.view.1 + i / w % h,
+                                  i / (w * h)))
+                        .collect()
+    }
+
+    fn in_view(&self, pos: Pos) -> bool {
+        pos.0 >= self.view.0 && pos.0 < self.view.0 + self.view_dim.0 &&
+            pos.1 >= self.view.1 && pos.1 < self.view.1 + self.view_dim.1
+    }
+
+    // Cells outside of the view are ignored
+    pub fn set_cell_state(&mut self, pos: Pos, state: CellState) {
+        if !self.in_view(pos) {
+            return;
+        }
 
-        match state {
+        let pxb = match state {
             CellState::Veiled => {
-                btn.button.set_from_pixbuf(&self.pxb_veiled);
+                self.pxb_veiled.clone()
             },
 
-            CellState::Flagged => {
-                btn.button.set_from_pixbuf(&self.pxb_flagged);
+            CellState::Flagged(1) => {
+                self.pxb_flagged.clone()
             },
 
-            CellState::Mine => {
-                btn.button.set_from_pixbuf(&self.pxb_mine);
+            CellState::Mine(1) => {
+                self.pxb_mine.clone()
             },
 
-            CellState::Safe(n) => {
-                btn.button.set_from_pixbuf(&self.pxb_safe[n]);
+            CellState::Safe(n) if n < self.pxb_safe.len() => {
+                self.pxb_safe[n].clone()
             },
-        }
 
+            _ => {
+                self.generated_pixbuf(state)
+            },
+        };
+
+        let btn = &mut self.buttons[pos.2][pos.1 - self.view.1]
+                                   [pos.0 - self.view.0];
+        btn.button.set_from_pixbuf(&pxb);
         btn.state = state;
     }
 
-    pub fn set_flag_count(&mut self, count: usize) {
-        self.mines_remaining.set_label(&format!("Mines flagged: {} / {}", count,
-                                                self.total_mine_count));
+    // Shows why @pos has been unveiled or flagged automatically in its
+    // tooltip (if it is in view)
+    fn show_reason(&self, pos: Pos, logic: &Logic) {
+        if self.in_view(pos) {
+            let reason = reason_label(logic, pos);
+            self.cell(pos).button.set_tooltip_text(reason.as_deref());
+        }
+    }
+
+    // Puts the mine probability on every veiled cell in view if that is
+    // enabled (and known), or shows them plainly otherwise
+    fn show_probabilities(&mut self, logic: &Logic) {
+        let probabilities = if self.probabilities_shown {
+            logic.mine_probabilities()
+        } else {
+            None
+        };
+
+        for pos in self.view_positions() {
+            if self.cell(pos).state != CellState::Veiled {
+                continue;
+            }
+
+            let pxb = match probabilities.as_ref().and_then(|p| p.get(&pos)) {
+                Some(&p) => {
+                    let percent = (p * 100.0).round() as usize;
+                    let veiled = &self.pxb_veiled;
+                    self.pxb_probability.entry(percent).or_insert_with(|| {
+                        label_pixbuf(veiled, &percent.to_string(), true)
+                    }).clone()
+                },
+
+                None => self.pxb_veiled.clone(),
+            };
+
+            self.cell(pos).button.set_from_pixbuf(&pxb);
+        }
+    }
+
+    // Highlights the cell the hint suggests and the numbers that justify
+    // it, and explains it below the field
+    fn show_hint(&mut self, logic: &Logic) {
+        let hint = match logic.hint() {
+            Some(hint) => hint,
+            None       => return,
+        };
+
+        let hinted: Vec<Pos> = match hint {
+            Hint::Safe(pos, ref reasons) | Hint::Mine(pos, _, ref reasons) =>
+                std::iter::once(pos).chain(reasons.iter().cloned())
+                                    .filter(|&p| self.in_view(p))
+                                    .collect(),
+
+            Hint::Guess => Vec::new(),
+        };
+
+        for &pos in &hinted {
+            self.cell(pos).button.drag_highlight();
+        }
+        self.hinted = Some(hinted);
+        self.analysis.set_label(&hint_label(&hint));
+    }
+
+    // Hints are only shown while the game is running, so there are no
+    // difficulty metrics to restore
+    fn clear_hint(&mut self) {
+        if let Some(hinted) = self.hinted.take() {
+            for pos in hinted {
+                self.cell(pos).button.drag_unhighlight();
+            }
+            self.analysis.set_label("");
+        }
+    }
+
+    fn generated_pixbuf(&mut self, state: CellState) -> Pixbuf {
+        if let Some(pxb) = self.pxb_generated.get(&state) {
+            return pxb.clone();
+        }
+
+        let pxb = match state {
+            // Holds mines, but how many is unknown
+            CellState::Flagged(0) =>
+                label_pixbuf(&self.pxb_flagged, "?", false),
+
+            CellState::Flagged(n) =>
+                label_pixbuf(&self.pxb_flagged, &n.to_string(), false),
+
+            CellState::Mine(n) =>
+                label_pixbuf(&self.pxb_mine, &n.to_string(), false),
+
+            CellState::Safe(n) =>
+                label_pixbuf(&self.pxb_safe[0], &n.to_string(), true),
+
+            CellState::Veiled =>
+                self.pxb_veiled.clone(),
+        };
+
+        self.pxb_generated.insert(state, pxb.clone());
+        pxb
     }
 }
@@ -1,21 +1,30 @@
 use std;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use game::{CellLabel, Game};
+use analysis::Analysis;
+use cellmap::{CellMap, CHUNK_SIZE};
+use game::{CellLabel, Game, Generator, Layout, LayoutChange};
+use topology::{Pos, Topology};
 
 
-#[derive(PartialEq, Clone, Copy)]
+// Flagged and Mine carry the number of mines (supposedly) in the cell;
+// Flagged(0) is a cell known to hold mines, but not how many
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum CellState {
     Veiled,
-    Flagged,
-    Mine,
+    Flagged(usize),
+    Mine(usize),
     Safe(usize),
 }
 
 #[derive(PartialEq, Clone, Copy)]
 enum ICellState { // Internal CellState (with additional states)
     Veiled,
-    Flagged,
-    Mine,
+    Flagged(usize),
+    Mine(usize),
     Safe(usize),
     DefinitelySafe,
 }
@@ -28,44 +37,232 @@ enum CellEnvironment {
 }
 
 
+// Bounds on how difficult a layout may be (None where there is no
+// bound)
+#[derive(Clone, Copy)]
+pub struct DifficultyTarget {
+    pub min_bbbv: Option<usize>,
+    pub max_bbbv: Option<usize>,
+    // Number of times the player must guess because no cell can be
+    // proven to be safe
+    pub min_guesses: Option<usize>,
+    pub max_guesses: Option<usize>,
+    // Most involved kind of deduction the player must make: 0 for
+    // none, 1 for looking at single numbers (or pairs of them), 2 for
+    // reasoning by contradiction or about the total number of mines
+    pub min_depth: Option<usize>,
+    pub max_depth: Option<usize>,
+
+    // Maximum number of layouts to try
+    pub attempts: usize,
+    // Maximum time to spend on trying them (None for no limit, which
+    // keeps the layout independent of the machine's speed)
+    pub time_limit: Option<Duration>,
+}
+
+// How the mine layout reacts to the player's moves
+#[derive(Clone, Copy, PartialEq)]
+pub enum Placement {
+    // Mines are placed on the first click and stay where they are
+    Fixed,
+    // Whether a clicked cell holds a mine is only really decided when
+    // it is clicked: If the player could have clicked a provably safe
+    // cell instead, the click hits a mine whenever some layout that
+    // agrees with all unveiled numbers allows it
+    Adversarial,
+    // If the player has to guess because no cell is provably safe,
+    // the mines are moved away from the clicked cell whenever some
+    // layout that agrees with all unveiled numbers allows it
+    Forgiving,
+}
+
+// On infinite fields, chains of automatic moves could go on forever, so
+// they are confined to this many cells around the cell the player has
+// clicked last
+const AUTO_REACH: usize = CHUNK_SIZE;
+
+// Reasoning about the total number of mines goes through all layouts of
+// the veiled cells, so it only starts once at most this many are left
+const ENDGAME_CELLS: usize = 40;
+
+// Maximum number of cells the layout search decides on before it gives
+// up (which makes it leave the layout as it is)
+const SEARCH_BUDGET: usize = 100000;
+
+// How the deduction engine has cleared a layout
+struct Solution {
+    guesses: usize,
+    depth: usize,
+}
+
+
+// Why a cell has been unveiled or flagged automatically
+#[derive(Clone, Copy, PartialEq)]
+pub enum Reason {
+    // The number on this cell has all of its mines flagged already
+    AllSafe(Pos),
+    // The number on this cell has just as many veiled cells around it
+    // as it has mines left
+    AllMines(Pos),
+    // The numbers on these two cells together say so, as the cells
+    // next to both can only hold so many of their mines
+    Pair(Pos, Pos),
+    // Follows from the total number of mines
+    MineCount,
+    // Anything else contradicts the numbers around it
+    Contradiction,
+}
+
+// Deferred work, so that cascades of unveiled cells do not recurse
+enum Task {
+    Unveil(Pos, Reason),
+    // Unveil or flag all cells around this one if its number (alone or
+    // together with a number nearby) says so
+    Resolve(Pos),
+}
+
+
+// A move the player can make without guessing
+pub enum Hint {
+    // The cell is safe, as the numbers on the cells given show
+    Safe(Pos, Vec<Pos>),
+    // The cell holds this many mines, as the numbers on the cells given
+    // show
+    Mine(Pos, usize, Vec<Pos>),
+    // No cell can be proven to be safe or to hold mines
+    Guess,
+}
+
 pub struct UIUpdate {
-    pub pos: (usize, usize),
+    pub pos: Pos,
     pub state: CellState,
 }
 
 #[derive(Clone)]
 struct GameState {
-    board: Vec<Vec<ICellState>>,
-    dim: (usize, usize),
+    // Packed ICellStates
+    board: CellMap,
+    topology: Rc<Topology>,
+    // Sum of all flags' mine counts
     flag_count: usize,
     mine_count: usize,
+    max_per_cell: usize,
+    unveiled_count: usize,
+
+    // Whether the total number of mines is taken into account (it is
+    // not while only looking at some of the numbers)
+    count_mines: bool,
+
+    // Number of checkpoints that have not been rolled back yet
+    checkpoints: usize,
+    // While there are checkpoints: the position and previous (packed)
+    // state of every cell that has been changed, in order
+    journal: Vec<(Pos, u8)>,
+}
+
+// Allows undoing all changes made to a GameState since this was taken
+struct Checkpoint {
+    journal_len: usize,
+    flag_count: usize,
     unveiled_count: usize,
 }
 
+// The state of a cell, the reason for it if it has been unveiled or
+// flagged automatically, and whether it is an uncounted mine
+type CellRecord = (ICellState, Option<Reason>, bool);
+
+// The parts of Logic besides the cells that actions change
+#[derive(Clone, Copy)]
+struct Status {
+    flag_count: usize,
+    unveiled_count: usize,
+    game_over: bool,
+    mines_spread: bool,
+    mined_cell_count: usize,
+    target_met: Option<bool>,
+}
+
+// Everything one action of the player has changed, so that it can be
+// undone and redone
+struct Action {
+    // Every cell changed, with what it was before and after the action
+    cells: Vec<(Pos, CellRecord, CellRecord)>,
+    // The same for the known future state, which may know more
+    future: Vec<(Pos, ICellState, ICellState)>,
+    // None if the mines have stayed where they were
+    layout: Option<LayoutChange>,
+    status: [Status; 2],
+}
+
+// What an action starts from, to find out what it changes
+struct ActionStart {
+    future: Checkpoint,
+    // Only taken if the action may change the layout
+    layout: Option<Layout>,
+    status: Status,
+}
+
 pub struct Logic {
     game: Game,
 
     auto_unveil: bool,
     touch_mode: bool,
+    // None if any layout is fine
+    target: Option<DifficultyTarget>,
+    placement: Placement,
 
     mines_spread: bool,
+    // Whether a layout in the target difficulty band has been found
+    target_met: Option<bool>,
     flag_count: usize,
     mine_count: usize,
+    max_per_cell: usize,
+    // Number of cells that contain mines (known once they are spread)
+    mined_cell_count: usize,
     unveiled_count: usize,
     game_state: GameState,
     known_future_state: GameState,
 
     game_over: bool,
     ui_updates: Vec<UIUpdate>,
+    tasks: Vec<Task>,
+    last_click: Pos,
+    // Why cells have been unveiled or flagged automatically
+    reasons: HashMap<Pos, Reason>,
+    // Cells that touch mode has found to hold mines, but not how many.
+    // They stay veiled as far as the deduction engine is concerned.
+    uncounted_mines: HashSet<Pos>,
+
+    // Actions that can be undone (most recent last), and those that
+    // have been undone and can be redone (most recently undone last)
+    history: Vec<Action>,
+    undone: Vec<Action>,
+    // Every cell changed during the current action, with what it was
+    // before the change
+    changes: Vec<(Pos, CellRecord)>,
+    // Whether hitting a mine can be undone
+    undo_mines: bool,
 }
 
 
 impl Logic {
-    pub fn new(game: Game, auto_unveil: bool, touch_mode: bool) -> Self {
-        let dim = game.get_dim();
+    pub fn new(game: Game, auto_unveil: bool, touch_mode: bool,
+               target: Option<DifficultyTarget>, placement: Placement)
+        -> Self
+    {
+        let topology = game.get_topology();
         let mine_count = game.get_mine_count();
+        let max_per_cell = game.get_max_per_cell();
+
+        if topology.is_infinite() && target.is_some() {
+            panic!("Infinite fields cannot have a target difficulty");
+        }
+
+        if topology.is_infinite() && placement != Placement::Fixed {
+            panic!("Mines on infinite fields cannot be relocated");
+        }
 
-        let game_state = GameState::new(dim, mine_count);
+        let game_state = GameState::new(topology, mine_count, max_per_cell);
         let future_state = game_state.clone();
 
         Logic {
@@ -73,60 +270,73 @@ impl Logic {
 
             auto_unveil: auto_unveil,
             touch_mode: touch_mode,
+            target: target,
+            placement: placement,
 
             mines_spread: false,
+            target_met: None,
             flag_count: 0,
             mine_count: mine_count,
+            max_per_cell: max_per_cell,
+            mined_cell_count: 0,
             unveiled_count: 0,
             game_state: game_state,
             known_future_state: future_state,
 
             game_over: false,
             ui_updates: Vec::<UIUpdate>::new(),
+            tasks: Vec::<Task>::new(),
+            last_click: (0, 0, 0),
+            reasons: HashMap::new(),
+            uncounted_mines: HashSet::new(),
+
+            history: Vec::new(),
+            undone: Vec::new(),
+            changes: Vec::new(),
+            undo_mines: false,
         }
     }
 
-    pub fn get_dim(&self) -> (usize, usize) {
-        self.game.get_dim()
+    // Allows taking back the click that hit a mine (for practicing)
+    pub fn set_undo_mines(&mut self, undo_mines: bool) {
+        self.undo_mines = undo_mines;
     }
 
-    fn pos_in_bounds(&self, pos: (i32, i32)) -> Option<(usize, usize)> {
-        let dim = self.game.get_dim();
+    pub fn get_dim(&self) -> (usize, usize, usize) {
+        self.game.get_dim()
+    }
 
-        if pos.0 >= 0 && (pos.0 as usize) < dim.0 &&
-           pos.1 >= 0 && (pos.1 as usize) < dim.1
-        {
-            Some((pos.0 as usize, pos.1 as usize))
-        } else {
-            None
-        }
+    pub fn get_topology(&self) -> Rc<Topology> {
+        self.game.get_topology()
     }
 
-    fn unveil_surrounding(&mut self, pos: (usize, usize)) {
-        let ipos = (pos.0 as i32, pos.1 as i32);
-        for yd in -1..2 {
-            for xd in -1..2 {
-                let dpos = (ipos.0 + xd, ipos.1 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    self.unveil(upos);
-                }
+    fn unveil_surrounding(&mut self, pos: Pos) {
+        for npos in self.game_state.topology.neighbors(pos) {
+            // Openings on infinite fields may never end; clicking on
+            // one of their zeros continues them
+            if !self.out_of_reach(npos) {
+                self.tasks.push(Task::Unveil(npos, Reason::AllSafe(pos)));
             }
         }
     }
 
-    fn flag_surrounding(&mut self, pos: (usize, usize)) {
-        let ipos = (pos.0 as i32, pos.1 as i32);
-        for yd in -1..2 {
-            for xd in -1..2 {
-                let dpos = (ipos.0 + xd, ipos.1 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    self.flag(upos);
-                }
-            }
+    // Returns whether automatic moves must leave @pos alone (see
+    // AUTO_REACH)
+    fn out_of_reach(&self, pos: Pos) -> bool {
+        self.game_state.topology.is_infinite() &&
+            (pos.0.abs_diff(self.last_click.0) > AUTO_REACH ||
+             pos.1.abs_diff(self.last_click.1) > AUTO_REACH)
+    }
+
+    fn flag_surrounding(&mut self, pos: Pos) {
+        // Only called when all surrounding cells must be full of mines
+        let count = self.max_per_cell;
+        for npos in self.game_state.topology.neighbors(pos) {
+            self.auto_flag(npos, count, Reason::AllMines(pos));
         }
     }
 
-    fn unveil(&mut self, pos: (usize, usize)) {
+    fn unveil(&mut self, pos: Pos) {
         assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
 
         if self.game_state.get(pos) != ICellState::Veiled {
@@ -134,38 +344,28 @@ impl Logic {
         }
 
         if !self.mines_spread {
-            self.game.spread_mines(pos);
-            self.mines_spread = true;
+            self.spread_mines(pos);
         }
 
         let label = self.game.get_cell_label(pos);
 
         let state = match label {
-            CellLabel::Mine    => ICellState::Mine,
+            CellLabel::Mine(n) => ICellState::Mine(n),
             CellLabel::Safe(n) => ICellState::Safe(n),
         };
-        self.game_state.set(pos, state);
-        self.known_future_state.set(pos, state);
+        self.set_cell(pos, state);
 
         match label {
-            CellLabel::Mine => {
+            CellLabel::Mine(_) => {
                 // Hit a mine, so the game has been lost
                 self.game_over = true;
 
                 /* Unveil all mines */
-                let dim = self.game.get_dim();
-                for y in 0..dim.1 {
-                    for x in 0..dim.0 {
-                        match self.game.get_cell_label((x, y)) {
-                            CellLabel::Mine =>
-                                self.ui_updates.push(UIUpdate {
-                                    pos: (x, y),
-                                    state: CellState::Mine,
-                                }),
-
-                            _ => ()
-                        }
-                    }
+                for mpos in self.game.mine_positions() {
+                    self.ui_updates.push(UIUpdate {
+                        pos: mpos,
+                        state: CellState::Mine(self.game.mines_at(mpos)),
+                    });
                 }
                 return;
             },
@@ -183,18 +383,25 @@ impl Logic {
             state: CellState::from(state)
         });
 
-        let dim = self.game.get_dim();
-        if self.unveiled_count + self.mine_count == dim.0 * dim.1 {
+        // (Never true on infinite fields)
+        if self.unveiled_count + self.mined_cell_count ==
+            self.game_state.topology.cell_count()
+        {
             // Unveiled all safe cells, so the game has been won
             self.game_over = true;
 
             if self.flag_count < self.mine_count {
                 // Auto-flag the rest
-                for y in 0..dim.1 {
-                    for x in 0..dim.0 {
-                        if self.game_state.get((x, y)) == ICellState::Veiled {
-                            self.flag((x, y));
-                        }
+                for fpos in self.game_state.topology.positions() {
+                    if !self.game_state.topology.is_active(fpos) ||
+                       self.game_state.get(fpos) != ICellState::Veiled
+                    {
+                        continue;
+                    }
+
+                    if let CellLabel::Mine(n) = self.game.get_cell_label(fpos)
+                    {
+                        self.auto_flag(fpos, n, Reason::MineCount);
                     }
                 }
             }
@@ -208,389 +415,1994 @@ impl Logic {
         }
     }
 
-    fn flag(&mut self, pos: (usize, usize)) {
-        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
-
-        if self.game_state.get(pos) != ICellState::Veiled {
-            return;
-        }
+    fn spread_mines(&mut self, first_click: Pos) {
+        self.game.spread_mines(first_click);
 
-        self.game_state.set(pos, ICellState::Flagged);
-        self.known_future_state.set(pos, ICellState::Flagged);
-        self.flag_count += 1;
-        self.ui_updates.push(UIUpdate { pos: pos, state: CellState::Flagged });
+        if let Some(target) = self.target {
+            let deadline = target.time_limit.map(|t| Instant::now() + t);
+            let mut attempts = 1;
+            let mut target_met = self.meets_target(&target, first_click);
 
-        if self.auto_unveil {
-            // Auto-develops the future state
-            self.unveil_around_sis(pos);
-        } else {
-            self.develop_future_state(pos);
-        }
-    }
+            while !target_met && attempts < target.attempts &&
+                  deadline.is_none_or(|deadline| Instant::now() < deadline)
+            {
+                self.game.spread_mines(first_click);
+                attempts += 1;
+                target_met = self.meets_target(&target, first_click);
+            }
 
-    fn unflag(&mut self, pos: (usize, usize)) {
-        if self.game_state.get(pos) != ICellState::Flagged {
-            return;
+            self.target_met = Some(target_met);
         }
 
-        self.game_state.set(pos, ICellState::Veiled);
-        self.known_future_state.set(pos, ICellState::Veiled);
-        self.flag_count -= 1;
-        self.ui_updates.push(UIUpdate { pos: pos, state: CellState::Veiled });
-
-        self.develop_future_state(pos);
+        self.mined_cell_count = self.game.get_mined_cell_count();
+        self.mines_spread = true;
     }
 
-    fn unveil_around_sis(&mut self, center: (usize, usize)) {
-        for yd in -1..2 {
-            for xd in -1..2 {
-                let dpos = (center.0 as i32 + xd, center.1 as i32 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    self.unveil_surrounding_if_safe(upos);
-                }
+    // Returns whether the current layout, when starting at
+    // @first_click, lies within the difficulty band given by @target
+    fn meets_target(&mut self, target: &DifficultyTarget,
+                    first_click: Pos)
+        -> bool
+    {
+        if target.min_bbbv.is_some() || target.max_bbbv.is_some() {
+            let bbbv = Analysis::new(&self.game).bbbv;
+            if !within(bbbv, target.min_bbbv, target.max_bbbv) {
+                return false;
             }
         }
-    }
 
-    fn develop_future_state(&mut self, center: (usize, usize)) {
-        if !self.known_future_state.environment_propagate(center) {
-            // User made an error somewhere, so let's just go back to
-            // whatever...
-            self.known_future_state = self.game_state.clone();
+        match self.solve(first_click) {
+            Some(solution) =>
+                within(solution.guesses,
+                       target.min_guesses, target.max_guesses) &&
+                within(solution.depth, target.min_depth, target.max_depth),
+
+            None => false,
         }
     }
 
-    fn definitely_mined(&self, pos: (usize, usize)) -> bool {
-        if self.known_future_state.get(pos) == ICellState::Flagged {
-            return true;
-        }
+    // Plays the current layout starting at @start, unveiling cells
+    // that the deduction engine has proven to be safe.  Whenever it is
+    // stuck, it guesses (correctly, as it knows the layout).  Returns
+    // None if @start is a mine (or if the engine contradicts itself).
+    fn solve(&mut self, start: Pos) -> Option<Solution> {
+        let mut state = GameState::new(self.game.get_topology(),
+                                       self.mine_count, self.max_per_cell);
+        let mut to_unveil = vec![start];
+        let mut solution = Solution {
+            guesses: 0,
+            depth: 0,
+        };
 
-        let mut hypothetical_state = self.known_future_state.clone();
+        loop {
+            while let Some(pos) = to_unveil.pop() {
+                match state.get(pos) {
+                    ICellState::Veiled | ICellState::DefinitelySafe => (),
+                    _ => continue,
+                }
 
-        hypothetical_state.set(pos, ICellState::DefinitelySafe);
-        return !hypothetical_state.environment_propagate(pos);
-    }
+                let n = match self.game.get_cell_label(pos) {
+                    CellLabel::Mine(_) => return None,
+                    CellLabel::Safe(n) => n,
+                };
 
-    fn unveil_surrounding_if_safe(&mut self, pos: (usize, usize))
-    {
-        match self.game_state.safe_cell_environment(pos) {
-            CellEnvironment::AllSafe  => self.unveil_surrounding(pos),
-            CellEnvironment::AllMines => self.flag_surrounding(pos),
+                state.set(pos, ICellState::Safe(n));
+                if !state.propagate(pos) || !state.environment_propagate(pos) {
+                    return None;
+                }
+            }
 
-            _ => ()
+            // Cells next to a zero are unveiled without any deduction
+            let mut deduced = false;
+            for pos in state.topology.positions() {
+                if state.get(pos) == ICellState::DefinitelySafe {
+                    to_unveil.push(pos);
+                    deduced |= !state.topology.neighbors(pos).into_iter()
+                                    .any(|npos| {
+                                        state.get(npos) == ICellState::Safe(0)
+                                    });
+                }
+            }
+
+            if !to_unveil.is_empty() {
+                if deduced {
+                    solution.depth = std::cmp::max(solution.depth, 1);
+                }
+            } else if state.deduce_by_contradiction() ||
+                      state.deduce_globally()
+            {
+                solution.depth = 2;
+            } else if let Some(pos) = self.safe_guess(&state) {
+                solution.guesses += 1;
+                to_unveil.push(pos);
+            } else {
+                break;
+            }
         }
+
+        Some(solution)
     }
 
-    pub fn pressed(&mut self, pos: (usize, usize)) {
-        if self.game_over {
+    // Gives the layout the chance to change before the player unveils
+    // @pos
+    fn reconsider_layout(&mut self, pos: Pos) {
+        if self.placement == Placement::Fixed {
             return;
         }
 
-        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
+        if !self.mines_spread {
+            self.spread_mines(pos);
+        }
 
-        match self.game_state.get(pos) {
-            ICellState::Veiled => {
-                if self.touch_mode {
-                    if self.definitely_mined(pos) {
-                        self.flag(pos);
-                    } else {
-                        self.unveil(pos);
-                    }
-                } else {
-                    self.unveil(pos);
+        match self.placement {
+            Placement::Fixed => (),
+
+            Placement::Adversarial => {
+                if self.game.mines_at(pos) == 0 && self.safe_move_exists() {
+                    let counts: Vec<usize> = (1..=self.max_per_cell).collect();
+                    self.relocate_mines(pos, &counts);
                 }
             },
 
-            ICellState::Safe(_) => {
-                self.unveil_surrounding_if_safe(pos);
+            Placement::Forgiving => {
+                if self.game.mines_at(pos) > 0 && !self.safe_move_exists() {
+                    self.relocate_mines(pos, &[0]);
+                }
             },
-
-            _ => ()
         }
     }
 
-    pub fn toggle_flag(&mut self, pos: (usize, usize)) {
-        if self.game_over {
-            return;
+    // Returns the current state without the player's flags (which may
+    // be wrong), i.e. only what the unveiled numbers say
+    fn revealed_state(&self) -> GameState {
+        let mut state = self.game_state.clone();
+        for pos in state.topology.positions() {
+            if let ICellState::Flagged(_) = state.get(pos) {
+                state.set(pos, ICellState::Veiled);
+            }
         }
+        state
+    }
 
-        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
-
-        match self.game_state.get(pos) {
-            ICellState::Veiled => self.flag(pos),
-            ICellState::Flagged => self.unflag(pos),
-
-            _ => ()
+    // Like revealed_state(), but keeps the flags that the engine has set
+    // itself
+    fn deduced_state(&self) -> GameState {
+        let mut state = self.game_state.clone();
+        for pos in state.topology.positions() {
+            if let ICellState::Flagged(_) = state.get(pos) {
+                if !self.reasons.contains_key(&pos) {
+                    state.set(pos, ICellState::Veiled);
+                }
+            }
         }
+        state
     }
 
-    pub fn get_mine_count(&self) -> usize {
-        self.mine_count
-    }
+    // Returns whether the deduction engine can prove some veiled cell
+    // to be safe
+    fn safe_move_exists(&self) -> bool {
+        let mut state = self.revealed_state();
+        let unveiled: Vec<Pos> =
+            state.topology.positions()
+                 .filter(|&pos| matches!(state.get(pos), ICellState::Safe(_)))
+                 .collect();
+        state.propagate_all(unveiled);
+
+        loop {
+            if state.topology.positions()
+                    .any(|pos| state.get(pos) == ICellState::DefinitelySafe)
+            {
+                return true;
+            }
 
-    pub fn get_flag_count(&self) -> usize {
-        self.flag_count
+            if !state.deduce_by_contradiction() && !state.deduce_globally() {
+                return false;
+            }
+        }
     }
 
-    pub fn get_ui_updates(&mut self) -> Vec<UIUpdate> {
-        std::mem::replace(&mut self.ui_updates, Vec::<UIUpdate>::new())
-    }
+    // Looks for a layout that agrees with all unveiled numbers and puts
+    // one of @counts mines (tried in order) into @pos.  If there is
+    // one, the mines are moved there (as few as possible), and true is
+    // returned.
+    fn relocate_mines(&mut self, pos: Pos, counts: &[usize]) -> bool {
+        let base = self.revealed_state();
+        let topology = self.game.get_topology();
+        let unveiled: Vec<Pos> =
+            topology.positions()
+                    .filter(|&pos| matches!(base.get(pos), ICellState::Safe(_)))
+                    .collect();
+
+        for &n in counts {
+            let mut state = base.clone();
+            state.set(pos, match n {
+                0 => ICellState::DefinitelySafe,
+                n => ICellState::Flagged(n),
+            });
+
+            if !state.sanity_check() || !state.propagate_all(unveiled.clone())
+            {
+                continue;
+            }
 
-    pub fn new_game(&mut self) {
-        self.game.new_game();
+            // Cells next to unveiled ones must agree with their numbers,
+            // all others can take any mines that are left
+            let (frontier, interior): (Vec<Pos>, Vec<Pos>) =
+                topology.positions()
+                        .filter(|&pos| topology.is_active(pos) &&
+                                       state.get(pos) == ICellState::Veiled)
+                        .partition(|&pos| state.next_to_unveiled(pos));
+
+            // Every cell first gets the number of mines it has in the
+            // current layout, so that as few mines as possible move
+            let mut decided = None;
+            state.visit_layouts(&frontier, |fpos| self.game.mines_at(fpos),
+                                SEARCH_BUDGET, |layout| {
+                let left = layout.mine_count - layout.flag_count;
+                if left > interior.len() * self.max_per_cell {
+                    return true;
+                }
 
-        self.game_state.clear();
-        self.known_future_state = self.game_state.clone();
-        self.mines_spread = false;
-        self.flag_count = 0;
-        self.unveiled_count = 0;
+                decided = Some(
+                    topology.positions()
+                            .filter(|&dpos| {
+                                base.get(dpos) == ICellState::Veiled
+                            })
+                            .filter_map(|dpos| match layout.get(dpos) {
+                                ICellState::DefinitelySafe => Some((dpos, 0)),
+                                ICellState::Flagged(n)     => Some((dpos, n)),
+
+                                _ => None,
+                            })
+                            .collect::<Vec<(Pos, usize)>>());
+                false
+            });
+
+            let decided = match decided {
+                Some(decided) => decided,
+                None          => continue,
+            };
+
+            self.game.relocate_mines(&decided, &interior);
+            self.mined_cell_count = self.game.get_mined_cell_count();
+            return true;
+        }
 
-        self.game_over = false;
+        false
     }
-}
-
-
-impl CellState {
-    fn from(ics: ICellState) -> Self {
-        match ics {
-            ICellState::Veiled  => CellState::Veiled,
-            ICellState::Flagged => CellState::Flagged,
-            ICellState::Mine    => CellState::Mine,
-            ICellState::Safe(n) => CellState::Safe(n),
 
-            _ => panic!("Cannot convert ICellState to CellState")
+    // Returns a cell that can be proven to be safe (preferably) or to
+    // hold mines, together with as few of the unveiled numbers that
+    // prove it as possible.  Ignores the player's flags, and leaves out
+    // mines that the player has flagged correctly already.  None if the
+    // game is over, or on infinite fields.
+    pub fn hint(&self) -> Option<Hint> {
+        if self.game_over || self.game_state.topology.is_infinite() {
+            return None;
         }
-    }
-}
-
-
-impl GameState {
-    fn new(dim: (usize, usize), mine_count: usize) -> Self {
-        let mut board = Vec::<Vec<ICellState>>::new();
 
-        for _ in 0..dim.1 {
-            let mut row = Vec::<ICellState>::new();
-            for _ in 0..dim.0 {
-                row.push(ICellState::Veiled);
+        let mut base = self.revealed_state();
+        let unveiled: Vec<Pos> =
+            base.topology.positions()
+                .filter(|&pos| matches!(base.get(pos), ICellState::Safe(_)))
+                .collect();
+
+        let mut state = base.clone();
+        state.propagate_all(unveiled.clone());
+
+        let mut candidates;
+        loop {
+            candidates =
+                state.topology.positions()
+                     .filter(|&pos| state.topology.is_active(pos))
+                     .filter_map(|pos| match state.get(pos) {
+                         ICellState::DefinitelySafe =>
+                             Some((pos, ICellState::DefinitelySafe)),
+
+                         ICellState::Flagged(n)
+                             if self.game_state.get(pos) !=
+                                ICellState::Flagged(n) =>
+                             Some((pos, ICellState::Flagged(n))),
+
+                         _ => None,
+                     })
+                     .collect::<Vec<(Pos, ICellState)>>();
+
+            if !candidates.is_empty() ||
+               (!state.deduce_by_contradiction() && !state.deduce_globally())
+            {
+                break;
             }
-            board.push(row);
         }
 
-        GameState {
-            board: board,
-            dim: dim,
-            flag_count: 0,
-            mine_count: mine_count,
-            unveiled_count: 0,
-        }
-    }
+        // Safe cells first
+        candidates.sort_by_key(|&(_, cstate)| {
+            cstate != ICellState::DefinitelySafe
+        });
 
-    fn clear(&mut self) {
-        for y in 0..self.dim.1 {
-            for x in 0..self.dim.0 {
-                self.board[y][x] = ICellState::Veiled;
+        let hint = |pos: Pos, cstate: ICellState, reasons: Vec<Pos>| {
+            match cstate {
+                ICellState::Flagged(n) => Hint::Mine(pos, n, reasons),
+                _                      => Hint::Safe(pos, reasons),
             }
-        }
-
-        self.flag_count = 0;
-        self.unveiled_count = 0;
-    }
-
-    fn get(&self, pos: (usize, usize)) -> ICellState {
-        self.board[pos.1][pos.0]
-    }
+        };
 
-    fn set(&mut self, pos: (usize, usize), state: ICellState) {
-        let old_state = self.get(pos);
+        // Look for the candidate that can be proven with the numbers
+        // closest to it
+        let mut radius = 1;
+        loop {
+            let mut grown = false;
 
-        if old_state == state {
-            return;
-        }
+            for &(pos, cstate) in &candidates {
+                let (mut numbers, complete) =
+                    base.numbers_around(pos, radius);
+                grown |= !complete;
 
-        match state {
-            ICellState::Flagged => {
-                self.flag_count += 1;
-            },
+                if !base.forced_by(pos, cstate, &numbers) {
+                    continue;
+                }
 
-            ICellState::DefinitelySafe | ICellState::Safe(_) => {
-                self.unveiled_count += 1;
-            },
+                // Drop the numbers that are not needed after all
+                let mut i = 0;
+                while i < numbers.len() {
+                    let npos = numbers.remove(i);
+                    if !base.forced_by(pos, cstate, &numbers) {
+                        numbers.insert(i, npos);
+                        i += 1;
+                    }
+                }
+                return Some(hint(pos, cstate, numbers));
+            }
 
-            _ => ()
+            if !grown {
+                break;
+            }
+            radius += 1;
         }
 
-        self.board[pos.1][pos.0] = state;
-    }
-
-    fn pos_in_bounds(&self, pos: (i32, i32)) -> Option<(usize, usize)> {
-        if pos.0 >= 0 && (pos.0 as usize) < self.dim.0 &&
-           pos.1 >= 0 && (pos.1 as usize) < self.dim.1
-        {
-            Some((pos.0 as usize, pos.1 as usize))
-        } else {
-            None
+        // Only the total number of mines proves it
+        match candidates.first() {
+            Some(&(pos, cstate)) => Some(hint(pos, cstate, unveiled)),
+            None                 => Some(Hint::Guess),
         }
     }
 
-    fn get_i32(&self, pos: (i32, i32)) -> Option<ICellState> {
-        if let Some(upos) = self.pos_in_bounds(pos) {
-            Some(self.board[upos.1][upos.0])
-        } else {
-            None
+    // Returns the probability of every veiled cell to hold a mine,
+    // given the numbers unveiled so far (but not the player's flags)
+    // and the total number of mines.  All layouts that agree with them
+    // are considered equally likely, which is only true with one mine
+    // per cell.  None for fields with more than one mine per cell, for
+    // infinite fields, and when there are too many layouts to go
+    // through.
+    pub fn mine_probabilities(&self) -> Option<HashMap<Pos, f64>> {
+        if self.max_per_cell > 1 || self.game_state.topology.is_infinite() {
+            return None;
         }
-    }
 
-    fn safe_cell_environment(&self, pos: (usize, usize)) -> CellEnvironment {
-        let n;
-        match self.get(pos) {
-            ICellState::Safe(x) => { n = x; },
+        let mut state = self.revealed_state();
+        let topology = self.game.get_topology();
+        let unveiled: Vec<Pos> =
+            topology.positions()
+                    .filter(|&pos| matches!(state.get(pos),
+                                            ICellState::Safe(_)))
+                    .collect();
+        if !state.propagate_all(unveiled) {
+            return None;
+        }
 
-            _ => return CellEnvironment::Unsure
-        };
+        // Cells that the numbers decide on their own are certain
+        let mut probabilities = HashMap::<Pos, f64>::new();
+        let mut veiled = Vec::<Pos>::new();
+        for pos in topology.positions() {
+            if !topology.is_active(pos) {
+                continue;
+            }
 
-        let mut flag_count = 0;
-        let mut potential_mine_count = 0;
-        let ipos = (pos.0 as i32, pos.1 as i32);
-        for yd in -1..2 {
-            for xd in -1..2 {
-                match self.get_i32((ipos.0 + xd, ipos.1 + yd)) {
-                    Some(ICellState::Veiled) => {
-                        potential_mine_count += 1;
-                    }
+            match state.get(pos) {
+                ICellState::DefinitelySafe => {
+                    probabilities.insert(pos, 0.0);
+                },
 
-                    Some(ICellState::Flagged) => {
-                        flag_count += 1;
-                        potential_mine_count += 1;
-                    },
+                ICellState::Flagged(_) => {
+                    probabilities.insert(pos, 1.0);
+                },
 
-                    Some(ICellState::Mine) => {
-                        potential_mine_count += 1;
-                    },
+                ICellState::Veiled => veiled.push(pos),
 
-                    _ => ()
-                };
+                _ => ()
             }
         }
 
-        if flag_count == n {
-            CellEnvironment::AllSafe
-        } else if potential_mine_count == n {
-            CellEnvironment::AllMines
-        } else if flag_count > n || potential_mine_count < n {
-            CellEnvironment::Impossible
-        } else {
-            CellEnvironment::Unsure
-        }
-    }
+        let (frontier, interior): (Vec<Pos>, Vec<Pos>) =
+            veiled.into_iter().partition(|&pos| state.next_to_unveiled(pos));
+        let left = self.mine_count.checked_sub(state.flag_count)?;
+
+        // Layouts of every group of cells that share numbers: entry k
+        // holds the share of layouts with k mines and, for every cell,
+        // the share of layouts with k mines that have one there
+        let mut components = Vec::<(Vec<Pos>, Vec<(f64, Vec<f64>)>)>::new();
+        for cells in state.components(&frontier) {
+            let base = state.flag_count;
+            let mut counts = vec![(0.0, vec![0.0; cells.len()]);
+                                  cells.len() + 1];
+            let mut total = 0.0;
+
+            let complete =
+                state.visit_layouts(&cells, |_| 0, SEARCH_BUDGET, |layout| {
+                    let entry = &mut counts[layout.flag_count - base];
+                    entry.0 += 1.0;
+                    for (i, &cpos) in cells.iter().enumerate() {
+                        if let ICellState::Flagged(_) = layout.get(cpos) {
+                            entry.1[i] += 1.0;
+                        }
+                    }
+                    total += 1.0;
+                    true
+                });
 
-    // Analyzes the environment of @pos.  If everything must be mines,
-    // they are all flagged.  If everything must be safe, it is marked
-    // as DefinitelySafe.
-    // If the state is impossible, false is returned.  Otherwise, true
-    // is returned.
-    fn propagate(&mut self, pos: (usize, usize)) -> bool {
-        match self.safe_cell_environment(pos) {
-            CellEnvironment::AllSafe =>
-                self.mark_environment_safe(pos),
+            if !complete || total == 0.0 {
+                return None;
+            }
 
-            CellEnvironment::AllMines =>
-                self.mark_environment_mines(pos),
+            // Only the ratios matter, and this keeps the numbers small
+            for entry in &mut counts {
+                entry.0 /= total;
+                for count in &mut entry.1 {
+                    *count /= total;
+                }
+            }
 
-            CellEnvironment::Unsure =>
-                true,
+            components.push((cells, counts));
+        }
 
-            CellEnvironment::Impossible =>
-                false,
+        // ln(n!) for all n up to the number of interior cells
+        let mut ln_factorial = vec![0.0; interior.len() + 1];
+        for n in 1..ln_factorial.len() {
+            ln_factorial[n] = ln_factorial[n - 1] + (n as f64).ln();
         }
-    }
 
-    fn environment_propagate(&mut self, center: (usize, usize)) -> bool {
-        if !self.sanity_check() {
-            return false;
+        // Number of ways to put the mines not in the frontier (when it
+        // holds @t of them) into the interior, relative to the largest
+        // such number
+        let ln_ways = |t: usize| -> Option<f64> {
+            let r = left.checked_sub(t)?;
+            if r > interior.len() {
+                return None;
+            }
+            Some(ln_factorial[interior.len()] - ln_factorial[r] -
+                 ln_factorial[interior.len() - r])
+        };
+        let max_frontier_mines = frontier.len();
+        let ln_max = (0..(max_frontier_mines + 1))
+                         .filter_map(&ln_ways)
+                         .fold(f64::NEG_INFINITY, f64::max);
+        if ln_max == f64::NEG_INFINITY {
+            return None;
         }
+        let ways = |t: usize| -> f64 {
+            ln_ways(t).map_or(0.0, |ln| (ln - ln_max).exp())
+        };
 
-        for yd in -1..2 {
-            for xd in -1..2 {
-                if xd == 0 && yd == 0 {
+        // Share of layouts of all components but @skip with t mines
+        let convolve = |skip: Option<usize>| -> Vec<f64> {
+            let mut dist = vec![1.0];
+            for (c, (_, counts)) in components.iter().enumerate() {
+                if Some(c) == skip {
                     continue;
                 }
 
-                let dpos = (center.0 as i32 + xd, center.1 as i32 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    if !self.propagate(upos) {
-                        return false;
+                let mut next = vec![0.0; dist.len() + counts.len() - 1];
+                for (t, &d) in dist.iter().enumerate() {
+                    for (k, &(share, _)) in counts.iter().enumerate() {
+                        next[t + k] += d * share;
                     }
                 }
+                dist = next;
             }
-        }
+            dist
+        };
 
-        return true;
-    }
+        let all = convolve(None);
+        let weight: Vec<f64> =
+            all.iter().enumerate().map(|(t, &d)| d * ways(t)).collect();
+        let z: f64 = weight.iter().sum();
+        if z == 0.0 {
+            return None;
+        }
 
-    fn mark_environment_safe(&mut self, pos: (usize, usize)) -> bool {
-        for yd in -1..2 {
-            for xd in -1..2 {
-                if xd == 0 && yd == 0 {
-                    continue;
+        for (c, (cells, counts)) in components.iter().enumerate() {
+            let others = convolve(Some(c));
+            for (i, &cpos) in cells.iter().enumerate() {
+                let mut p = 0.0;
+                for (k, (_, mined)) in counts.iter().enumerate() {
+                    for (t, &d) in others.iter().enumerate() {
+                        p += mined[i] * d * ways(k + t);
+                    }
                 }
+                probabilities.insert(cpos, p / z);
+            }
+        }
+
+        if !interior.is_empty() {
+            let p = weight.iter().enumerate()
+                          .map(|(t, &w)| {
+                              w * left.saturating_sub(t) as f64 /
+                                  interior.len() as f64
+                          })
+                          .sum::<f64>() / z;
+
+            for ipos in interior {
+                probabilities.insert(ipos, p);
+            }
+        }
+
+        Some(probabilities)
+    }
+
+    // Returns a veiled safe cell, preferably one next to an unveiled
+    // cell (None if there is no veiled safe cell left)
+    fn safe_guess(&self, state: &GameState) -> Option<Pos> {
+        let candidates: Vec<Pos> =
+            state.topology.positions()
+                 .filter(|&pos| state.topology.is_active(pos) &&
+                                state.get(pos) == ICellState::Veiled &&
+                                self.game.mines_at(pos) == 0)
+                 .collect();
+
+        candidates.iter().find(|&&pos| state.next_to_unveiled(pos))
+                  .or(candidates.first())
+                  .cloned()
+    }
+
+    // Flags @pos as holding @count mines
+    fn flag(&mut self, pos: Pos, count: usize) {
+        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
+
+        if self.game_state.get(pos) != ICellState::Veiled {
+            return;
+        }
+
+        self.place_flag(pos, count, true);
+    }
+
+    // Flags @pos as holding @count mines, for which @reason is the
+    // reason
+    fn auto_flag(&mut self, pos: Pos, count: usize, reason: Reason) {
+        let veiled = self.game_state.get(pos) == ICellState::Veiled;
+
+        self.flag(pos, count);
+        if veiled {
+            self.reasons.insert(pos, reason);
+        }
+    }
+
+    // Changes the number of mines that the flag on @pos stands for.
+    // @resolve tells whether @count is what the player really means, so
+    // automatic unveiling may rely on it.
+    fn reflag(&mut self, pos: Pos, count: usize, resolve: bool) {
+        match self.game_state.get(pos) {
+            ICellState::Flagged(_) => self.place_flag(pos, count, resolve),
+
+            _ => ()
+        }
+    }
+
+    fn place_flag(&mut self, pos: Pos, count: usize, resolve: bool) {
+        if let ICellState::Flagged(old_count) = self.game_state.get(pos) {
+            self.flag_count -= old_count;
+        }
+
+        self.set_cell(pos, ICellState::Flagged(count));
+        self.flag_count += count;
+        self.ui_updates.push(UIUpdate {
+            pos: pos,
+            state: CellState::Flagged(count),
+        });
+
+        if self.auto_unveil && resolve {
+            // Auto-develops the future state
+            self.unveil_around_sis(pos);
+        } else {
+            self.develop_future_state(pos);
+        }
+    }
+
+    fn unflag(&mut self, pos: Pos) {
+        let count = match self.game_state.get(pos) {
+            ICellState::Flagged(count) => count,
+
+            _ => return
+        };
+
+        self.set_cell(pos, ICellState::Veiled);
+        self.flag_count -= count;
+        self.ui_updates.push(UIUpdate { pos: pos, state: CellState::Veiled });
+
+        self.develop_future_state(pos);
+    }
+
+    fn unveil_around_sis(&mut self, center: Pos) {
+        self.tasks.push(Task::Resolve(center));
+        for npos in self.game_state.topology.neighbors(center) {
+            self.tasks.push(Task::Resolve(npos));
+        }
+    }
+
+    // Works through all deferred tasks, including those that are added
+    // in the process
+    fn run_tasks(&mut self) {
+        loop {
+            while let Some(task) = self.tasks.pop() {
+                match task {
+                    Task::Unveil(pos, reason) => {
+                        let veiled =
+                            self.game_state.get(pos) == ICellState::Veiled;
+
+                        self.unveil(pos);
+                        if veiled {
+                            self.reasons.insert(pos, reason);
+                        }
+                    },
+
+                    Task::Resolve(pos) => self.resolve(pos),
+                }
+            }
+
+            // Only once nothing else is left to do, as this is the most
+            // costly kind of deduction
+            if !self.auto_unveil || !self.mines_spread || self.game_over ||
+               !self.resolve_globally()
+            {
+                break;
+            }
+        }
+    }
+
+    // Unveils or flags all cells whose state follows from the total
+    // number of mines.  Returns whether there were any.
+    fn resolve_globally(&mut self) -> bool {
+        if self.game_state.topology.is_infinite() {
+            // There is no total number of mines
+            return false;
+        }
+
+        // The player's flags may be wrong, and cells they have flagged
+        // are left alone
+        let deductions: Vec<(Pos, ICellState)> =
+            match self.deduced_state().global_deductions() {
+                Some(deductions) =>
+                    deductions.into_iter()
+                              .filter(|&(pos, _)| {
+                                  self.game_state.get(pos) ==
+                                      ICellState::Veiled
+                              })
+                              .collect(),
+
+                None => return false,
+            };
+
+        for &(pos, state) in &deductions {
+            match state {
+                ICellState::DefinitelySafe => {
+                    self.tasks.push(Task::Unveil(pos, Reason::MineCount));
+                },
+
+                ICellState::Flagged(n) =>
+                    self.auto_flag(pos, n, Reason::MineCount),
+
+                _ => ()
+            }
+        }
+
+        !deductions.is_empty()
+    }
+
+    fn develop_future_state(&mut self, center: Pos) {
+        if !self.known_future_state.environment_propagate(center) {
+            // User made an error somewhere, so let's just go back to
+            // whatever...
+            self.known_future_state.reset_to(&self.game_state);
+        }
+    }
+
+    fn definitely_mined(&mut self, pos: Pos) -> bool {
+        if let ICellState::Flagged(_) = self.known_future_state.get(pos) {
+            return true;
+        }
+
+        !self.known_future_state.consistent_with(pos,
+                                                 ICellState::DefinitelySafe)
+    }
+
+    // Returns the number of mines that @pos must hold according to what
+    // is known (None if more than one number is possible)
+    fn proven_mine_count(&mut self, pos: Pos) -> Option<usize> {
+        if let ICellState::Flagged(n) = self.known_future_state.get(pos) {
+            return Some(n);
+        }
+
+        let mut counts = (1..=self.max_per_cell).filter(|&n| {
+            self.known_future_state.consistent_with(pos,
+                                                    ICellState::Flagged(n))
+        });
+
+        match (counts.next(), counts.next()) {
+            (Some(n), None) => Some(n),
+            _               => None,
+        }
+    }
+
+    // Shows that @pos holds mines without claiming how many, so nothing
+    // can be deduced from it
+    fn mark_uncounted(&mut self, pos: Pos) {
+        if !self.uncounted_mines.contains(&pos) {
+            self.changes.push((pos, self.cell_record(pos)));
+            self.uncounted_mines.insert(pos);
+        }
+
+        self.ui_updates.push(UIUpdate {
+            pos: pos,
+            state: CellState::Flagged(0),
+        });
+    }
+
+    fn resolve(&mut self, pos: Pos) {
+        if self.out_of_reach(pos) {
+            return;
+        }
+
+        if let CellEnvironment::Unsure =
+            self.game_state.safe_cell_environment(pos)
+        {
+            let deductions = match self.game_state.pair_deductions(pos) {
+                Some(deductions) => deductions,
+                None             => return,
+            };
+
+            for (npos, state, opos) in deductions {
+                let reason = Reason::Pair(pos, opos);
+                match state {
+                    ICellState::DefinitelySafe => {
+                        self.tasks.push(Task::Unveil(npos, reason));
+                    },
+
+                    ICellState::Flagged(n) => self.auto_flag(npos, n, reason),
+
+                    _ => ()
+                }
+            }
+        } else {
+            self.unveil_surrounding_if_safe(pos);
+        }
+    }
+
+    fn unveil_surrounding_if_safe(&mut self, pos: Pos)
+    {
+        match self.game_state.safe_cell_environment(pos) {
+            CellEnvironment::AllSafe  => self.unveil_surrounding(pos),
+            CellEnvironment::AllMines => self.flag_surrounding(pos),
+
+            _ => ()
+        }
+    }
+
+    pub fn pressed(&mut self, pos: Pos) {
+        if self.game_over {
+            return;
+        }
+
+        self.last_click = pos;
+        let start = self.start_action();
+
+        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
+
+        match self.game_state.get(pos) {
+            ICellState::Veiled => {
+                if self.touch_mode {
+                    if self.definitely_mined(pos) {
+                        match self.proven_mine_count(pos) {
+                            Some(count) =>
+                                self.auto_flag(pos, count,
+                                               Reason::Contradiction),
+                            None =>
+                                self.mark_uncounted(pos),
+                        }
+                    } else {
+                        self.reconsider_layout(pos);
+                        self.unveil(pos);
+                    }
+                } else {
+                    self.reconsider_layout(pos);
+                    self.unveil(pos);
+                }
+            },
+
+            ICellState::Safe(_) => {
+                self.unveil_surrounding_if_safe(pos);
+            },
+
+            _ => ()
+        }
+
+        self.run_tasks();
+        self.record_action(start);
+    }
+
+    pub fn toggle_flag(&mut self, pos: Pos) {
+        if self.game_over {
+            return;
+        }
+
+        self.last_click = pos;
+        let start = self.start_action();
+
+        assert!(self.game_state.get(pos) != ICellState::DefinitelySafe);
+
+        // Cycles through all possible mine counts.  With more than one
+        // mine per cell, any count may just be a step on the way to the
+        // one the player means, so nothing is unveiled based on it
+        // (set_flag() is the way to get there directly).
+        let resolve = self.max_per_cell == 1;
+        match self.game_state.get(pos) {
+            ICellState::Veiled =>
+                self.place_flag(pos, 1, resolve),
+
+            ICellState::Flagged(n) if n < self.max_per_cell =>
+                self.reflag(pos, n + 1, resolve),
+
+            ICellState::Flagged(_) =>
+                self.unflag(pos),
+
+            _ => ()
+        }
+
+        self.forget_reason(pos);
+
+        self.run_tasks();
+        self.record_action(start);
+    }
+
+    // Takes back the player's last action, including everything that
+    // followed from it automatically.  Once a mine has been hit, that
+    // is only possible if set_undo_mines() allows it.  Returns whether
+    // there was anything to undo.
+    pub fn undo(&mut self) -> bool {
+        if self.is_lost() && !self.undo_mines {
+            return false;
+        }
+
+        match self.history.pop() {
+            Some(action) => {
+                self.replay(&action, false);
+                self.undone.push(action);
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    // Makes the action undone last again.  Returns whether there was
+    // anything to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.undone.pop() {
+            Some(action) => {
+                self.replay(&action, true);
+                self.history.push(action);
+                true
+            },
+
+            None => false,
+        }
+    }
+
+    // Changes the state of @pos as the player sees it, remembering what
+    // it was before so the change can be undone
+    fn set_cell(&mut self, pos: Pos, state: ICellState) {
+        self.changes.push((pos, self.cell_record(pos)));
+
+        self.game_state.set(pos, state);
+        self.known_future_state.set(pos, state);
+        self.uncounted_mines.remove(&pos);
+    }
+
+    fn cell_record(&self, pos: Pos) -> CellRecord {
+        (self.game_state.get(pos), self.reasons.get(&pos).cloned(),
+         self.uncounted_mines.contains(&pos))
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            flag_count: self.flag_count,
+            unveiled_count: self.unveiled_count,
+            game_over: self.game_over,
+            mines_spread: self.mines_spread,
+            mined_cell_count: self.mined_cell_count,
+            target_met: self.target_met,
+        }
+    }
+
+    fn is_lost(&self) -> bool {
+        self.game_over && !self.is_won()
+    }
+
+    fn start_action(&mut self) -> ActionStart {
+        // Mines are spread on the first click, and may be relocated on
+        // every click unless their placement is fixed
+        let layout = if !self.mines_spread ||
+                        self.placement != Placement::Fixed
+        {
+            Some(self.game.layout())
+        } else {
+            None
+        };
+
+        ActionStart {
+            future: self.known_future_state.checkpoint(),
+            layout: layout,
+            status: self.status(),
+        }
+    }
+
+    // Turns the changes made since @start into an action that can be
+    // undone
+    fn record_action(&mut self, start: ActionStart) {
+        let mut cells = Vec::new();
+        let mut seen = HashSet::<Pos>::new();
+
+        for (pos, before) in std::mem::take(&mut self.changes) {
+            let after = self.cell_record(pos);
+
+            if seen.insert(pos) && before != after {
+                cells.push((pos, before, after));
+            }
+        }
+
+        let mut future = Vec::new();
+        seen.clear();
+        for (pos, before) in self.known_future_state.commit(start.future) {
+            let before = ICellState::unpack(before);
+            let after = self.known_future_state.get(pos);
+
+            if seen.insert(pos) && before != after {
+                future.push((pos, before, after));
+            }
+        }
+
+        let layout = start.layout.and_then(|l| self.game.layout_change(l));
+
+        if cells.is_empty() && future.is_empty() && layout.is_none() {
+            return;
+        }
+
+        self.history.push(Action {
+            cells: cells,
+            future: future,
+            layout: layout,
+            status: [start.status, self.status()],
+        });
+        self.undone.clear();
+    }
+
+    // Puts every cell changed by @action into the state it had before
+    // (or, for @redo, after) it
+    fn replay(&mut self, action: &Action, redo: bool) {
+        let was_lost = self.is_lost();
+
+        // The mines shown so far must be veiled again if they move
+        let mut shown = match was_lost {
+            true  => self.game.mine_positions(),
+            false => Vec::new(),
+        };
+
+        if let Some(ref layout) = action.layout {
+            self.game.restore_layout(layout, redo);
+        }
+
+        for &(pos, before, after) in &action.cells {
+            let (state, reason, uncounted) = if redo { after } else { before };
+
+            self.game_state.set(pos, state);
+            match reason {
+                Some(reason) => self.reasons.insert(pos, reason),
+                None         => self.reasons.remove(&pos),
+            };
+
+            let state = if uncounted {
+                self.uncounted_mines.insert(pos);
+                CellState::Flagged(0)
+            } else {
+                self.uncounted_mines.remove(&pos);
+                CellState::from(state)
+            };
+            self.ui_updates.push(UIUpdate {
+                pos: pos,
+                state: state,
+            });
+        }
+
+        for &(pos, before, after) in &action.future {
+            let state = if redo { after } else { before };
+            self.known_future_state.set(pos, state);
+        }
+
+        let status = action.status[redo as usize];
+        self.flag_count = status.flag_count;
+        self.unveiled_count = status.unveiled_count;
+        self.game_over = status.game_over;
+        self.mines_spread = status.mines_spread;
+        self.mined_cell_count = status.mined_cell_count;
+        self.target_met = status.target_met;
+
+        // All mines are shown while the game is lost
+        let lost = self.is_lost();
+        if lost != was_lost {
+            shown.extend(self.game.mine_positions());
+
+            for mpos in shown {
+                let state = if lost && self.game.mines_at(mpos) > 0 {
+                    CellState::Mine(self.game.mines_at(mpos))
+                } else {
+                    self.get_cell_state(mpos)
+                };
+
+                self.ui_updates.push(UIUpdate {
+                    pos: mpos,
+                    state: state,
+                });
+            }
+        }
+    }
+
+    // Flags @pos as holding exactly @count mines, or removes its flag if
+    // @count is 0.  Counts beyond the maximum per cell are ignored.
+    pub fn set_flag(&mut self, pos: Pos, count: usize) {
+        if self.game_over || count > self.max_per_cell {
+            return;
+        }
+
+        match self.game_state.get(pos) {
+            ICellState::Veiled | ICellState::Flagged(_) => (),
+
+            _ => return
+        }
+
+        self.last_click = pos;
+        let start = self.start_action();
+
+        if count == 0 {
+            self.unflag(pos);
+        } else {
+            self.place_flag(pos, count, true);
+        }
+
+        self.forget_reason(pos);
+
+        self.run_tasks();
+        self.record_action(start);
+    }
+
+    fn forget_reason(&mut self, pos: Pos) {
+        match self.game_state.get(pos) {
+            ICellState::Veiled | ICellState::Flagged(_) => {
+                // The player knows better
+                self.reasons.remove(&pos);
+            },
+
+            _ => ()
+        }
+    }
+
+    pub fn get_mine_count(&self) -> usize {
+        self.mine_count
+    }
+
+    pub fn get_flag_count(&self) -> usize {
+        self.flag_count
+    }
+
+    // Returns the number of safe cells that have been unveiled
+    pub fn get_unveiled_count(&self) -> usize {
+        self.unveiled_count
+    }
+
+    // Returns what @pos currently shows (once the game has been lost,
+    // that includes all mines placed so far)
+    pub fn get_cell_state(&self, pos: Pos) -> CellState {
+        match self.game_state.get(pos) {
+            ICellState::Veiled if self.game_over &&
+                                  self.game.mines_at(pos) > 0 =>
+                CellState::Mine(self.game.mines_at(pos)),
+
+            ICellState::Veiled if self.uncounted_mines.contains(&pos) =>
+                CellState::Flagged(0),
+
+            state => CellState::from(state),
+        }
+    }
+
+    // Returns why @pos has been unveiled or flagged automatically (None
+    // if the player did that)
+    pub fn get_reason(&self, pos: Pos) -> Option<Reason> {
+        self.reasons.get(&pos).cloned()
+    }
+
+    pub fn get_seed(&self) -> Option<u64> {
+        self.game.get_seed()
+    }
+
+    pub fn get_generator(&self) -> Generator {
+        self.game.get_generator()
+    }
+
+    // Returns whether the current layout lies within the target
+    // difficulty band (None if the mines have not been spread yet or
+    // if there is no target)
+    pub fn get_target_met(&self) -> Option<bool> {
+        self.target_met
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        self.game_over
+    }
+
+    // Returns whether the game is over because all safe cells have been
+    // unveiled
+    pub fn is_won(&self) -> bool {
+        self.game_over &&
+            self.unveiled_count + self.mined_cell_count ==
+                self.game_state.topology.cell_count()
+    }
+
+    // Saves the mine layout to a board file (only possible once the
+    // mines have been spread)
+    pub fn save_board(&self, path: &Path) -> Result<(), String> {
+        if !self.mines_spread {
+            return Err(String::from("Mines have not been spread yet"));
+        }
+
+        self.game.save(path)
+    }
+
+    // Returns difficulty metrics for the mine layout (None if the mines
+    // have not been spread yet or the field is infinite)
+    pub fn analyze(&self) -> Option<Analysis> {
+        if !self.mines_spread || self.game_state.topology.is_infinite() {
+            return None;
+        }
+
+        Some(Analysis::new(&self.game))
+    }
+
+    pub fn get_ui_updates(&mut self) -> Vec<UIUpdate> {
+        std::mem::replace(&mut self.ui_updates, Vec::<UIUpdate>::new())
+    }
+
+    pub fn new_game(&mut self) {
+        self.game.new_game();
+
+        self.game_state.clear();
+        self.known_future_state.clone_from(&self.game_state);
+        self.mines_spread = false;
+        self.target_met = None;
+        self.mined_cell_count = 0;
+        self.flag_count = 0;
+        self.unveiled_count = 0;
+        self.reasons.clear();
+        self.uncounted_mines.clear();
+        self.history.clear();
+        self.undone.clear();
+
+        self.game_over = false;
+    }
+}
+
+
+impl DifficultyTarget {
+    pub fn new() -> Self {
+        DifficultyTarget {
+            min_bbbv: None,
+            max_bbbv: None,
+            min_guesses: None,
+            max_guesses: None,
+            min_depth: None,
+            max_depth: None,
+
+            attempts: 1000,
+            time_limit: None,
+        }
+    }
+}
+
+
+// Returns whether @value lies within the given (optional) bounds
+fn within(value: usize, min: Option<usize>, max: Option<usize>) -> bool {
+    min.unwrap_or(0) <= value && value <= max.unwrap_or(usize::MAX)
+}
+
+
+impl ICellState {
+    // Every state fits into a single byte, because cells hold at most
+    // nine mines and have at most 26 neighbors
+    fn pack(self) -> u8 {
+        match self {
+            ICellState::Veiled         => 0,
+            ICellState::DefinitelySafe => 1,
+            ICellState::Flagged(n)     => 1 + n as u8,
+            ICellState::Mine(n)        => 10 + n as u8,
+            ICellState::Safe(n)        => 20 + n as u8,
+        }
+    }
+
+    fn unpack(byte: u8) -> Self {
+        match byte {
+            0       => ICellState::Veiled,
+            1       => ICellState::DefinitelySafe,
+            2..=10  => ICellState::Flagged(byte as usize - 1),
+            11..=19 => ICellState::Mine(byte as usize - 10),
+            _       => ICellState::Safe(byte as usize - 20),
+        }
+    }
+}
+
+
+impl CellState {
+    fn from(ics: ICellState) -> Self {
+        match ics {
+            ICellState::Veiled  => CellState::Veiled,
+            ICellState::Flagged(n) => CellState::Flagged(n),
+            ICellState::Mine(n)    => CellState::Mine(n),
+            ICellState::Safe(n) => CellState::Safe(n),
+
+            _ => panic!("Cannot convert ICellState to CellState")
+        }
+    }
+}
+
+
+impl GameState {
+    fn new(topology: Rc<Topology>, mine_count: usize, max_per_cell: usize)
+        -> Self
+    {
+        GameState {
+            board: CellMap::new(&topology, ICellState::Veiled.pack()),
+            topology: topology,
+            flag_count: 0,
+            mine_count: mine_count,
+            max_per_cell: max_per_cell,
+            unveiled_count: 0,
+
+            count_mines: true,
+
+            checkpoints: 0,
+            journal: Vec::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.board.fill(ICellState::Veiled.pack());
+
+        self.flag_count = 0;
+        self.unveiled_count = 0;
+    }
+
+    fn get(&self, pos: Pos) -> ICellState {
+        ICellState::unpack(self.board.get(pos))
+    }
+
+    fn set(&mut self, pos: Pos, state: ICellState) {
+        let old_state = self.get(pos);
+
+        if old_state == state {
+            return;
+        }
+
+        match old_state {
+            ICellState::Flagged(n) => {
+                self.flag_count -= n;
+            },
+
+            ICellState::DefinitelySafe | ICellState::Safe(_) => {
+                self.unveiled_count -= 1;
+            },
+
+            _ => ()
+        }
+
+        match state {
+            ICellState::Flagged(n) => {
+                self.flag_count += n;
+            },
+
+            ICellState::DefinitelySafe | ICellState::Safe(_) => {
+                self.unveiled_count += 1;
+            },
+
+            _ => ()
+        }
+
+        if self.checkpoints > 0 {
+            self.journal.push((pos, self.board.get(pos)));
+        }
+        self.board.set(pos, state.pack());
+    }
+
+    // Changes made from now on can be undone with rollback()
+    fn checkpoint(&mut self) -> Checkpoint {
+        self.checkpoints += 1;
+
+        Checkpoint {
+            journal_len: self.journal.len(),
+            flag_count: self.flag_count,
+            unveiled_count: self.unveiled_count,
+        }
+    }
+
+    // Restores the state from when @checkpoint was taken.  Checkpoints
+    // must be rolled back in the reverse order they were taken in.
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        while self.journal.len() > checkpoint.journal_len {
+            let (pos, cell) = self.journal.pop().unwrap();
+            self.board.set(pos, cell);
+        }
+
+        self.flag_count = checkpoint.flag_count;
+        self.unveiled_count = checkpoint.unveiled_count;
+        self.checkpoints -= 1;
+    }
 
-                let dpos = (pos.0 as i32 + xd, pos.1 as i32 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    match self.get(upos) {
-                        ICellState::Veiled => {
-                            self.set(upos, ICellState::DefinitelySafe);
-                            if !self.sanity_check() {
-                                return false;
-                            }
-                            if !self.environment_propagate(upos) {
-                                return false;
-                            }
-                        },
-
-                        _ => ()
+    // Stops recording the changes made since @checkpoint, keeping them,
+    // and returns the position and previous (packed) state of every
+    // cell changed since then, in order
+    fn commit(&mut self, checkpoint: Checkpoint) -> Vec<(Pos, u8)> {
+        self.checkpoints -= 1;
+        self.journal.split_off(checkpoint.journal_len)
+    }
+
+    // Makes this state equal to @other cell by cell, so that the changes
+    // are recorded like any other
+    fn reset_to(&mut self, other: &GameState) {
+        let mut positions = self.board.stored_positions();
+        if self.topology.is_infinite() {
+            positions.extend(other.board.stored_positions());
+        }
+
+        for pos in positions {
+            if self.get(pos) != other.get(pos) {
+                self.set(pos, other.get(pos));
+            }
+        }
+    }
+
+    // Returns whether setting @pos to @state does not lead to a
+    // contradiction.  Leaves the state unchanged.
+    fn consistent_with(&mut self, pos: Pos, state: ICellState) -> bool {
+        let checkpoint = self.checkpoint();
+
+        self.set(pos, state);
+        let consistent = self.environment_propagate(pos);
+
+        self.rollback(checkpoint);
+        consistent
+    }
+
+    fn safe_cell_environment(&self, pos: Pos) -> CellEnvironment {
+        let n;
+        match self.get(pos) {
+            ICellState::Safe(x) => { n = x; },
+
+            _ => return CellEnvironment::Unsure
+        };
+
+        // Both count mines, not cells
+        let mut flag_count = 0;
+        let mut potential_mine_count = 0;
+        for npos in self.topology.neighbors(pos) {
+            match self.get(npos) {
+                ICellState::Veiled => {
+                    potential_mine_count += self.max_per_cell;
+                }
+
+                ICellState::Flagged(m) => {
+                    flag_count += m;
+                    potential_mine_count += m;
+                },
+
+                ICellState::Mine(m) => {
+                    potential_mine_count += m;
+                },
+
+                _ => ()
+            };
+        }
+
+        if flag_count == n {
+            CellEnvironment::AllSafe
+        } else if potential_mine_count == n {
+            CellEnvironment::AllMines
+        } else if flag_count > n || potential_mine_count < n {
+            CellEnvironment::Impossible
+        } else {
+            CellEnvironment::Unsure
+        }
+    }
+
+    // Analyzes the environment of @pos.  If everything must be mines,
+    // they are all flagged (as full of mines).  If everything must be
+    // safe, it is marked as DefinitelySafe.  The environments of all
+    // cells changed this way are analyzed in turn.
+    // If the state is impossible, false is returned.  Otherwise, true
+    // is returned.
+    fn propagate(&mut self, pos: Pos) -> bool {
+        self.propagate_all(vec![pos])
+    }
+
+    fn environment_propagate(&mut self, center: Pos) -> bool {
+        if !self.sanity_check() {
+            return false;
+        }
+
+        let neighbors = self.topology.neighbors(center);
+        self.propagate_all(neighbors)
+    }
+
+    // Works like propagate() for every cell in @pending.  Uses a
+    // worklist instead of recursion, so that long chains of deductions
+    // on large fields cannot overflow the stack.
+    fn propagate_all(&mut self, mut pending: Vec<Pos>) -> bool {
+        while let Some(pos) = pending.pop() {
+            let deductions: Vec<(Pos, ICellState)> =
+                match self.safe_cell_environment(pos) {
+                    CellEnvironment::AllSafe => {
+                        self.topology.neighbors(pos).into_iter()
+                            .map(|npos| (npos, ICellState::DefinitelySafe))
+                            .collect()
+                    },
+
+                    CellEnvironment::AllMines => {
+                        let state = ICellState::Flagged(self.max_per_cell);
+                        self.topology.neighbors(pos).into_iter()
+                            .map(|npos| (npos, state))
+                            .collect()
+                    },
+
+                    CellEnvironment::Unsure => {
+                        match self.pair_deductions(pos) {
+                            Some(deductions) =>
+                                deductions.into_iter()
+                                    .map(|(npos, state, _)| (npos, state))
+                                    .collect(),
+
+                            None => return false,
+                        }
+                    },
+
+                    CellEnvironment::Impossible => return false,
+                };
+
+            for (npos, state) in deductions {
+                if self.get(npos) == ICellState::Veiled {
+                    self.set(npos, state);
+                    if !self.sanity_check() {
+                        return false;
                     }
+                    pending.extend(self.topology.neighbors(npos));
                 }
             }
         }
 
-        return true;
+        true
+    }
+
+    // Returns the veiled neighbors of the unveiled cell @pos and how
+    // many mines they hold according to its number (None if @pos is
+    // not unveiled or has more mines around it than its number says)
+    fn open_constraint(&self, pos: Pos) -> Option<(Vec<Pos>, usize)> {
+        let n = match self.get(pos) {
+            ICellState::Safe(n) => n,
+
+            _ => return None
+        };
+
+        let mut veiled = Vec::<Pos>::new();
+        let mut known_mines = 0;
+        for npos in self.topology.neighbors(pos) {
+            match self.get(npos) {
+                ICellState::Veiled => veiled.push(npos),
+
+                ICellState::Flagged(m) | ICellState::Mine(m) => {
+                    known_mines += m;
+                },
+
+                _ => ()
+            }
+        }
+
+        n.checked_sub(known_mines).map(|left| (veiled, left))
     }
 
-    fn mark_environment_mines(&mut self, pos: (usize, usize)) -> bool {
-        for yd in -1..2 {
-            for xd in -1..2 {
-                if xd == 0 && yd == 0 {
+    // Compares the number on @pos with every other number that shares
+    // veiled neighbors with it.  Whatever the mines in the shared cells
+    // may be, the cells next to only one of the two may then be forced
+    // to be all safe or all full of mines (as in the 1-2-1 pattern).
+    // Returns the cells found that way, their states and the other
+    // number they follow from, or None if two numbers contradict each
+    // other.
+    fn pair_deductions(&self, pos: Pos)
+        -> Option<Vec<(Pos, ICellState, Pos)>>
+    {
+        let mut deductions = Vec::<(Pos, ICellState, Pos)>::new();
+
+        let (a_cells, a_left) = match self.open_constraint(pos) {
+            Some(constraint) => constraint,
+            None             => return Some(deductions),
+        };
+
+        let mut others = Vec::<Pos>::new();
+        for &vpos in &a_cells {
+            for opos in self.topology.neighbors(vpos) {
+                if opos != pos && !others.contains(&opos) {
+                    others.push(opos);
+                }
+            }
+        }
+
+        let m = self.max_per_cell;
+        for opos in others {
+            let (b_cells, b_left) = match self.open_constraint(opos) {
+                Some(constraint) => constraint,
+                None             => continue,
+            };
+
+            let shared = a_cells.iter().filter(|c| b_cells.contains(c))
+                                .count();
+            let only_a = a_cells.len() - shared;
+            let only_b = b_cells.len() - shared;
+
+            // Bounds on the number of mines in the shared cells
+            let lo = std::cmp::max(a_left.saturating_sub(only_a * m),
+                                   b_left.saturating_sub(only_b * m));
+            let hi = std::cmp::min(std::cmp::min(a_left, b_left), shared * m);
+            if lo > hi {
+                return None;
+            }
+
+            for &(cells, left, other) in &[(&a_cells, a_left, &b_cells),
+                                           (&b_cells, b_left, &a_cells)]
+            {
+                let only: Vec<Pos> =
+                    cells.iter().filter(|c| !other.contains(c))
+                         .cloned().collect();
+
+                let state = if left == lo {
+                    ICellState::DefinitelySafe
+                } else if left - hi == only.len() * m {
+                    ICellState::Flagged(m)
+                } else {
                     continue;
+                };
+
+                deductions.extend(only.into_iter()
+                                      .map(|c| (c, state, opos)));
+            }
+        }
+
+        Some(deductions)
+    }
+
+    // Goes through all ways to decide the number of mines in every
+    // veiled cell of @frontier such that this state stays consistent,
+    // and calls @visit with the resulting state for each of them until
+    // it returns false.  Every cell first gets @first(cell) mines, then
+    // all other numbers in order.  Gives up after deciding on @budget
+    // cells, and returns whether it did not have to.  Leaves the state
+    // as it was.
+    fn visit_layouts<F, V>(&mut self, frontier: &[Pos], first: F,
+                           mut budget: usize, mut visit: V)
+        -> bool
+        where F: Fn(Pos) -> usize,
+              V: FnMut(&GameState) -> bool
+    {
+        // Every cell that has been decided on: its index in @frontier,
+        // which option to try next, and the checkpoint taken before
+        // the current option was applied
+        let mut decisions = Vec::<(usize, usize, Option<Checkpoint>)>::new();
+        let mut stopped = false;
+        let mut gave_up = false;
+
+        loop {
+            if !stopped {
+                // All cells before the one decided on last have been
+                // decided already (or have been given a state by
+                // propagation)
+                let start = decisions.last().map_or(0, |d| d.0 + 1);
+                let next = frontier[start..].iter()
+                               .position(|&pos| {
+                                   self.get(pos) == ICellState::Veiled
+                               })
+                               .map(|offset| start + offset);
+
+                match next {
+                    Some(i) => decisions.push((i, 0, None)),
+                    None    => stopped = !visit(self),
+                }
+            }
+
+            // Apply the next option to the cell decided on last; go back
+            // to the cells before it when it has none left
+            loop {
+                let applied = match decisions.last_mut() {
+                    Some(decision) => {
+                        if let Some(checkpoint) = decision.2.take() {
+                            self.rollback(checkpoint);
+                        }
+
+                        if stopped || decision.1 > self.max_per_cell {
+                            None
+                        } else if budget == 0 {
+                            gave_up = true;
+                            None
+                        } else {
+                            budget -= 1;
+
+                            let dpos = frontier[decision.0];
+                            let preferred = first(dpos);
+                            let n = match decision.1 {
+                                0                   => preferred,
+                                k if k <= preferred => k - 1,
+                                k                   => k,
+                            };
+                            decision.1 += 1;
+
+                            let checkpoint = self.checkpoint();
+                            self.set(dpos, match n {
+                                0 => ICellState::DefinitelySafe,
+                                n => ICellState::Flagged(n),
+                            });
+                            let consistent = self.environment_propagate(dpos);
+                            decision.2 = Some(checkpoint);
+
+                            Some(consistent)
+                        }
+                    },
+
+                    None => return !gave_up,
+                };
+
+                match applied {
+                    Some(true)  => break,
+                    Some(false) => (),
+                    None        => { decisions.pop(); },
+                }
+            }
+        }
+    }
+
+    // Uses the total number of mines: If none are left, all veiled
+    // cells are safe; if there is just enough room for them, all are
+    // full of mines.  Once few cells are left, goes through all layouts
+    // of the veiled cells next to unveiled ones, keeping those that
+    // leave a number of mines for the other veiled cells that fits.
+    // Every cell that has the same number of mines in all of them is
+    // returned with its state.  Returns None if there is no layout.
+    fn global_deductions(&mut self) -> Option<Vec<(Pos, ICellState)>> {
+        let mut deductions = Vec::<(Pos, ICellState)>::new();
+        if self.topology.is_infinite() {
+            return Some(deductions);
+        }
+
+        let m = self.max_per_cell;
+        let left = self.mine_count.checked_sub(self.flag_count)?;
+        let veiled: Vec<Pos> =
+            self.topology.positions()
+                .filter(|&pos| self.topology.is_active(pos) &&
+                               self.get(pos) == ICellState::Veiled)
+                .collect();
+
+        if left == 0 || left == veiled.len() * m {
+            let state = match left {
+                0 => ICellState::DefinitelySafe,
+                _ => ICellState::Flagged(m),
+            };
+            deductions.extend(veiled.into_iter().map(|pos| (pos, state)));
+            return Some(deductions);
+        }
+
+        if veiled.len() > ENDGAME_CELLS {
+            return Some(deductions);
+        }
+
+        let (frontier, interior): (Vec<Pos>, Vec<Pos>) =
+            veiled.into_iter().partition(|&pos| self.next_to_unveiled(pos));
+
+        // Bit n is set if a cell may hold n mines
+        let mut possible = vec![0u16; frontier.len()];
+        let mut found = false;
+        // Whether all other cells are empty/full in every layout
+        let mut interior_empty = true;
+        let mut interior_full = true;
+
+        let complete = self.visit_layouts(&frontier, |_| 0, SEARCH_BUDGET,
+                                          |layout| {
+            let left = layout.mine_count - layout.flag_count;
+            if left > interior.len() * m {
+                return true;
+            }
+
+            for (i, &fpos) in frontier.iter().enumerate() {
+                if let ICellState::Flagged(n) = layout.get(fpos) {
+                    possible[i] |= 1 << n;
+                } else {
+                    possible[i] |= 1;
+                }
+            }
+
+            found = true;
+            interior_empty &= left == 0;
+            interior_full &= left == interior.len() * m;
+            true
+        });
+
+        if !complete {
+            return Some(deductions);
+        } else if !found {
+            return None;
+        }
+
+        for (i, fpos) in frontier.into_iter().enumerate() {
+            if possible[i].count_ones() == 1 {
+                deductions.push((fpos, match possible[i].trailing_zeros() {
+                    0 => ICellState::DefinitelySafe,
+                    n => ICellState::Flagged(n as usize),
+                }));
+            }
+        }
+
+        if interior_empty || interior_full {
+            let state = if interior_empty {
+                ICellState::DefinitelySafe
+            } else {
+                ICellState::Flagged(m)
+            };
+            deductions.extend(interior.into_iter().map(|pos| (pos, state)));
+        }
+
+        Some(deductions)
+    }
+
+    // Records what global_deductions() finds in this state.  Returns
+    // whether anything was found.
+    fn deduce_globally(&mut self) -> bool {
+        let deductions = match self.global_deductions() {
+            Some(deductions) => deductions,
+            None             => return false,
+        };
+
+        for &(pos, state) in &deductions {
+            self.set(pos, state);
+            if !self.environment_propagate(pos) {
+                break;
+            }
+        }
+
+        !deductions.is_empty()
+    }
+
+    // Returns the unveiled numbers that are at most @radius steps away
+    // from @pos, where every step goes from a veiled cell to a number
+    // next to it, or from a number to a veiled cell next to it.  Also
+    // returns whether there are no more numbers further away.
+    fn numbers_around(&self, pos: Pos, radius: usize) -> (Vec<Pos>, bool) {
+        let mut numbers = Vec::<Pos>::new();
+        let mut cells = vec![pos];
+        let mut seen = HashSet::<Pos>::new();
+        seen.insert(pos);
+
+        for _ in 0..radius {
+            let mut next_cells = Vec::<Pos>::new();
+
+            for &cpos in &cells {
+                for npos in self.topology.neighbors(cpos) {
+                    if !matches!(self.get(npos), ICellState::Safe(_)) ||
+                       !seen.insert(npos)
+                    {
+                        continue;
+                    }
+
+                    numbers.push(npos);
+                    for vpos in self.topology.neighbors(npos) {
+                        if self.get(vpos) == ICellState::Veiled &&
+                           seen.insert(vpos)
+                        {
+                            next_cells.push(vpos);
+                        }
+                    }
+                }
+            }
+
+            if next_cells.is_empty() {
+                return (numbers, true);
+            }
+            cells = next_cells;
+        }
+
+        // Whether there are numbers left can only be seen in the next step
+        let complete = cells.iter().all(|&cpos| {
+            self.topology.neighbors(cpos).into_iter().all(|npos| {
+                !matches!(self.get(npos), ICellState::Safe(_)) ||
+                    seen.contains(&npos)
+            })
+        });
+        (numbers, complete)
+    }
+
+    // Returns whether the numbers on @numbers alone force @pos into
+    // @state (DefinitelySafe or Flagged(n)), i.e. whether every other
+    // state of @pos contradicts them
+    fn forced_by(&mut self, pos: Pos, state: ICellState, numbers: &[Pos])
+        -> bool
+    {
+        // The cells the numbers talk about
+        let mut cells = vec![pos];
+        for &npos in numbers {
+            for vpos in self.topology.neighbors(npos) {
+                if self.get(vpos) == ICellState::Veiled &&
+                   !cells.contains(&vpos)
+                {
+                    cells.push(vpos);
+                }
+            }
+        }
+
+        // Only the numbers given count, not the total number of mines
+        let outer = self.checkpoint();
+        self.count_mines = false;
+
+        // All other numbers next to those cells are ignored (but the
+        // cells they are on are still known to be safe)
+        for &cpos in &cells {
+            for npos in self.topology.neighbors(cpos) {
+                if matches!(self.get(npos), ICellState::Safe(_)) &&
+                   !numbers.contains(&npos)
+                {
+                    self.set(npos, ICellState::DefinitelySafe);
                 }
+            }
+        }
+
+        let mut forced = true;
+        for n in 0..(self.max_per_cell + 1) {
+            let alternative = match n {
+                0 => ICellState::DefinitelySafe,
+                n => ICellState::Flagged(n),
+            };
+            if alternative == state {
+                continue;
+            }
+
+            let checkpoint = self.checkpoint();
+            self.set(pos, alternative);
+
+            let mut possible = false;
+            let complete = !self.environment_propagate(pos) ||
+                self.visit_layouts(&cells, |_| 0, SEARCH_BUDGET, |_| {
+                    possible = true;
+                    false
+                });
+            self.rollback(checkpoint);
+
+            if possible || !complete {
+                forced = false;
+                break;
+            }
+        }
+
+        self.count_mines = true;
+        self.rollback(outer);
+        forced
+    }
+
+    // Splits @cells into groups such that cells in different groups
+    // never share an unveiled neighbor
+    fn components(&self, cells: &[Pos]) -> Vec<Vec<Pos>> {
+        let included: HashSet<Pos> = cells.iter().cloned().collect();
+        let mut seen = HashSet::<Pos>::new();
+        let mut components = Vec::<Vec<Pos>>::new();
+
+        for &start in cells {
+            if !seen.insert(start) {
+                continue;
+            }
+
+            let mut component = Vec::<Pos>::new();
+            let mut stack = vec![start];
+            while let Some(pos) = stack.pop() {
+                component.push(pos);
 
-                let dpos = (pos.0 as i32 + xd, pos.1 as i32 + yd);
-                if let Some(upos) = self.pos_in_bounds(dpos) {
-                    match self.get(upos) {
-                        ICellState::Veiled => {
-                            self.set(upos, ICellState::Flagged);
-                            if !self.sanity_check() {
-                                return false;
-                            }
-                            if !self.environment_propagate(upos) {
-                                return false;
-                            }
-                        },
-
-                        _ => ()
+                for upos in self.topology.neighbors(pos) {
+                    if !matches!(self.get(upos), ICellState::Safe(_)) {
+                        continue;
+                    }
+
+                    for cpos in self.topology.neighbors(upos) {
+                        if included.contains(&cpos) && seen.insert(cpos) {
+                            stack.push(cpos);
+                        }
                     }
                 }
             }
+
+            components.push(component);
         }
 
-        return true;
+        components
+    }
+
+    // Tries to flag and to unveil every veiled cell next to an
+    // unveiled one, and checks whether that leads to a contradiction.
+    // If so, the opposite must be true, which is then recorded in this
+    // state.  Returns whether anything was found.
+    fn deduce_by_contradiction(&mut self) -> bool {
+        let mut progress = false;
+
+        for pos in self.topology.positions() {
+            if self.get(pos) != ICellState::Veiled ||
+               !self.topology.is_active(pos) ||
+               !self.next_to_unveiled(pos)
+            {
+                continue;
+            }
+
+            // Mine counts that do not lead to a contradiction
+            let mut possible = Vec::<usize>::new();
+            for n in 0..(self.max_per_cell + 1) {
+                let state = match n {
+                    0 => ICellState::DefinitelySafe,
+                    n => ICellState::Flagged(n),
+                };
+
+                if self.consistent_with(pos, state) {
+                    possible.push(n);
+                }
+            }
+
+            if possible == [0] {
+                self.set(pos, ICellState::DefinitelySafe);
+                progress = true;
+            } else if possible.len() == 1 {
+                self.set(pos, ICellState::Flagged(possible[0]));
+                if !self.environment_propagate(pos) {
+                    return progress;
+                }
+                progress = true;
+            }
+        }
+
+        progress
+    }
+
+    fn next_to_unveiled(&self, pos: Pos) -> bool {
+        self.topology.neighbors(pos).into_iter()
+            .any(|npos| matches!(self.get(npos), ICellState::Safe(_)))
     }
 
     fn sanity_check(&self) -> bool {
+        if self.topology.is_infinite() || !self.count_mines {
+            // There is no limit on the total number of mines
+            return true;
+        }
+
         if self.flag_count > self.mine_count {
             return false;
         }
 
-        if self.unveiled_count + self.mine_count > self.dim.0 * self.dim.1 {
+        // Every cell that may still contain mines can hold at most
+        // max_per_cell of them
+        let cell_count = self.topology.cell_count();
+        if self.unveiled_count > cell_count ||
+           (cell_count - self.unveiled_count) * self.max_per_cell <
+               self.mine_count
+        {
             return false;
         }
 
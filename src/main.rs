@@ -1,14 +1,30 @@
-extern crate gdk_pixbuf;
-extern crate gtk;
+// Much of the game logic only serves the GUI
+#![cfg_attr(not(feature = "gui"), allow(dead_code))]
+
+#[cfg(feature = "gui")] extern crate cairo;
+#[cfg(feature = "gui")] extern crate gdk;
+#[cfg(feature = "gui")] extern crate gdk_pixbuf;
+#[cfg(feature = "gui")] extern crate gtk;
 extern crate rand;
 
+mod analysis;
+mod autoplay;
+mod cellmap;
 mod game;
-mod gui;
+#[cfg(feature = "gui")] mod gui;
 mod logic;
+mod rng;
+mod simulation;
+mod topology;
+
+use std::time::Duration;
 
-use game::Game;
-use gui::GUI;
-use logic::Logic;
+use analysis::Analysis;
+use game::{FirstClick, Game, Generator};
+#[cfg(feature = "gui")] use gui::GUI;
+use logic::{DifficultyTarget, Logic, Placement};
+use simulation::Simulation;
+use topology::{Grid, Neighborhood, Topology};
 
 
 fn main() {
@@ -19,13 +35,93 @@ fn main() {
 
     let mut width = 30;
     let mut height = 16;
+    let mut depth = 1;
     let mut mine_count = 99;
+    let mut seed = None;
+    let mut generator = Generator::CandidateList;
+    let mut target = None::<DifficultyTarget>;
+    let mut attempts = None;
+    let mut time_limit = None;
+    let mut first_click = FirstClick::Block;
+    let mut board_file = None;
+    let mut grid = Grid::Square;
+    let mut neighborhood = Neighborhood::Moore;
+    let mut wrap = false;
+    let mut mask_file = None;
+    let mut max_per_cell = 1;
+    let mut infinite = false;
+    let mut placement = Placement::Fixed;
+
+    let mut analyze = false;
+    let mut simulate = None;
 
     let mut auto_unveil = false;
     let mut touch_mode = false;
+    let mut undo_mines = false;
+    let mut autoplay_delay = 200;
 
     for arg in args {
-        if arg.starts_with("--") {
+        if let Some(value) = arg.strip_prefix("--seed=") {
+            let (g, s) = Generator::parse_seed(value).unwrap();
+            generator = g;
+            seed = Some(s);
+        } else if let Some(value) = arg.strip_prefix("--first-click=") {
+            first_click = match value {
+                "none"  => FirstClick::Unprotected,
+                "cell"  => FirstClick::Cell,
+                "block" => FirstClick::Block,
+
+                _ => FirstClick::Opening(value.parse::<usize>().unwrap()),
+            };
+        } else if let Some(value) = arg.strip_prefix("--board=") {
+            board_file = Some(String::from(value));
+        } else if let Some(value) = arg.strip_prefix("--max-mines=") {
+            max_per_cell = value.parse::<usize>().unwrap();
+        } else if let Some(value) = arg.strip_prefix("--mask=") {
+            mask_file = Some(String::from(value));
+        } else if let Some(value) = arg.strip_prefix("--layers=") {
+            depth = value.parse::<usize>().unwrap();
+        } else if let Some(value) = arg.strip_prefix("--neighbors=") {
+            neighborhood = match value {
+                "moore"      => Neighborhood::Moore,
+                "orthogonal" => Neighborhood::Orthogonal,
+                "knight"     => Neighborhood::Knight,
+                "radius2"    => Neighborhood::Radius2,
+
+                _ => panic!("Unknown neighborhood {}", value),
+            };
+        } else if let Some(value) = arg.strip_prefix("--no-guess=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.max_guesses = Some(0);
+            attempts = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--min-3bv=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.min_bbbv = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--max-3bv=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.max_bbbv = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--min-guesses=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.min_guesses = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--max-guesses=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.max_guesses = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--min-depth=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.min_depth = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--max-depth=") {
+            let target = target.get_or_insert_with(DifficultyTarget::new);
+            target.max_depth = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--attempts=") {
+            attempts = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--time-limit=") {
+            let seconds = value.parse::<f64>().unwrap();
+            time_limit = Some(Duration::from_secs_f64(seconds));
+        } else if let Some(value) = arg.strip_prefix("--simulate=") {
+            simulate = Some(value.parse::<usize>().unwrap());
+        } else if let Some(value) = arg.strip_prefix("--autoplay-delay=") {
+            autoplay_delay = value.parse::<u32>().unwrap();
+        } else if arg.starts_with("--") {
             match arg.as_ref() {
                 "--auto-unveil" => {
                     auto_unveil = true;
@@ -35,6 +131,40 @@ fn main() {
                     touch_mode = true;
                 },
 
+                "--hex" => {
+                    grid = Grid::Hex;
+                },
+
+                "--torus" => {
+                    wrap = true;
+                },
+
+                "--infinite" => {
+                    infinite = true;
+                },
+
+                "--no-guess" => {
+                    let target =
+                        target.get_or_insert_with(DifficultyTarget::new);
+                    target.max_guesses = Some(0);
+                },
+
+                "--analyze" => {
+                    analyze = true;
+                },
+
+                "--lazy-mines" => {
+                    placement = Placement::Adversarial;
+                },
+
+                "--forgiving" => {
+                    placement = Placement::Forgiving;
+                },
+
+                "--practice" => {
+                    undo_mines = true;
+                },
+
                 "--help" => {
                     println!("Available switches:");
                     println!("  --auto-unveil:");
@@ -52,6 +182,192 @@ fn main() {
                     println!("    would think it is safe or a mine.  Depending \
                                   on the answer, the");
                     println!("    field will be unveiled of flagged.");
+                    println!("");
+                    println!("  --seed=<g>:<n>:");
+                    println!("    Use the given seed for the first game, as \
+                                  shown by the GUI: <g> is");
+                    println!("    the generator that spreads the mines, <n> \
+                                  the seed proper.  The");
+                    println!("    same seed, field size, mine count and \
+                                  first click always result");
+                    println!("    in the same mine layout.  Generator 1 \
+                                  draws random cells until");
+                    println!("    it finds free ones, generator 2 (used for \
+                                  random seeds) draws");
+                    println!("    from a list of the cells that are still \
+                                  free, which stays fast");
+                    println!("    for dense fields.  A bare <n> means \
+                                  generator 1, which older");
+                    println!("    releases used, so their seeds can be \
+                                  played again.");
+                    println!("");
+                    println!("  --hex:");
+                    println!("    Use a field of hexagonal cells (with six \
+                                  neighbors each).");
+                    println!("");
+                    println!("  --torus:");
+                    println!("    Let the field wrap around at its edges, so \
+                                  cells on the left");
+                    println!("    border neighbor cells on the right border, \
+                                  and cells on the top");
+                    println!("    border neighbor cells on the bottom \
+                                  border.");
+                    println!("");
+                    println!("  --infinite:");
+                    println!("    Play on an endless field, whose mines are \
+                                  placed as you go.  The");
+                    println!("    field dimensions given are the size of the \
+                                  view (use the arrow");
+                    println!("    keys to move it), and the mine count is the \
+                                  number of mines in");
+                    println!("    every {0}×{0} block (default: 52).  The \
+                                  score is the number of", cellmap::CHUNK_SIZE);
+                    println!("    cells cleared before hitting a mine.");
+                    println!("");
+                    println!("  --layers=<n>:");
+                    println!("    Stack <n> layers of the field on top of each \
+                                  other (default: 1).");
+                    println!("    Every cell then also neighbors the nine \
+                                  cells around it in the");
+                    println!("    layers directly above and below.  Layers are \
+                                  shown side by");
+                    println!("    side.");
+                    println!("");
+                    println!("  --neighbors=<moore|orthogonal|knight|\
+                                  radius2>:");
+                    println!("    Choose which cells count as neighbors on a \
+                                  square grid: the");
+                    println!("    surrounding 3×3 block (default), only the \
+                                  four orthogonally");
+                    println!("    adjacent cells, the eight cells a knight \
+                                  could move to, or the");
+                    println!("    surrounding 5×5 block.");
+                    println!("");
+                    println!("  --mask=<file>:");
+                    println!("    Only create cells where the given mask file \
+                                  has a '#'.  Positions");
+                    println!("    marked with '-' or a space hold no cell.  \
+                                  The field dimensions");
+                    println!("    are taken from the mask, so only the mine \
+                                  count may be given.");
+                    println!("");
+                    println!("  --max-mines=<n>:");
+                    println!("    Every cell can hold up to <n> mines \
+                                  (default: 1, at most 9).");
+                    println!("    Numbers count mines instead of mined cells.  \
+                                  Right-click a flag");
+                    println!("    repeatedly to raise the number of mines it \
+                                  stands for, or press");
+                    println!("    a number key over a cell to flag it with \
+                                  that many mines right away");
+                    println!("    (0 removes the flag).  With --auto-unveil, \
+                                  only the latter");
+                    println!("    unveils cells around the flag.");
+                    println!("");
+                    println!("  --no-guess[=<attempts>]:");
+                    println!("    After the first click, keep generating new \
+                                  layouts until one is");
+                    println!("    found that can be solved without guessing, \
+                                  but try at most");
+                    println!("    <attempts> layouts (default: 1000).  If none \
+                                  is found, the last");
+                    println!("    one is used.  Same as --max-guesses=0 \
+                                  --attempts=<attempts>.");
+                    println!("");
+                    println!("  --min-3bv=<n>, --max-3bv=<n>:");
+                    println!("    Only accept layouts that need at least/at \
+                                  most <n> clicks to");
+                    println!("    clear without flagging anything.");
+                    println!("");
+                    println!("  --min-guesses=<n>, --max-guesses=<n>:");
+                    println!("    Only accept layouts in which at least/at \
+                                  most <n> times no cell");
+                    println!("    can be proven to be safe, so the player has \
+                                  to guess.");
+                    println!("");
+                    println!("  --min-depth=<n>, --max-depth=<n>:");
+                    println!("    Only accept layouts whose most involved \
+                                  deduction is at least/at");
+                    println!("    most as deep as <n>: 0 for none at all, 1 \
+                                  for looking at single");
+                    println!("    numbers or pairs of them, 2 for reasoning \
+                                  by contradiction or");
+                    println!("    about the total number of mines.");
+                    println!("");
+                    println!("  --attempts=<n>:");
+                    println!("    Try at most <n> layouts (default: 1000) to \
+                                  find one within the");
+                    println!("    bounds given above.  If none is found, the \
+                                  last one is used.");
+                    println!("");
+                    println!("  --time-limit=<s>:");
+                    println!("    Stop trying layouts after <s> seconds (by \
+                                  default, only the");
+                    println!("    number of attempts is limited).  Which \
+                                  layout a seed results in");
+                    println!("    then depends on the speed of the \
+                                  machine.");
+                    println!("");
+                    println!("  --lazy-mines:");
+                    println!("    Decide whether a cell is a mine only when \
+                                  it is clicked.  If some");
+                    println!("    other cell could have been proven to be \
+                                  safe, every cell that");
+                    println!("    may be a mine according to the numbers \
+                                  shown is one.  Guesses");
+                    println!("    that could not be avoided are not \
+                                  punished.");
+                    println!("");
+                    println!("  --forgiving:");
+                    println!("    When no cell can be proven to be safe, so \
+                                  you have to guess,");
+                    println!("    move the mines away from the cell you \
+                                  click whenever the");
+                    println!("    numbers shown allow it.");
+                    println!("");
+                    println!("  --practice:");
+                    println!("    Ctrl+Z takes back your last action (and \
+                                  Ctrl+Shift+Z redoes");
+                    println!("    it).  With this switch, that includes a \
+                                  click that hit a mine.");
+                    println!("");
+                    println!("  --first-click=<none|cell|block|<n>>:");
+                    println!("    Protect nothing, only the clicked cell, the \
+                                  clicked cell and its");
+                    println!("    neighbors (default), or ensure that the \
+                                  first click unveils at");
+                    println!("    least <n> cells.");
+                    println!("");
+                    println!("  --board=<file>:");
+                    println!("    Play the mine layout stored in the given \
+                                  board file instead of a");
+                    println!("    random one.  The layout of a finished game \
+                                  can be saved to such");
+                    println!("    a file by pressing 's'.");
+                    println!("");
+                    println!("  --analyze:");
+                    println!("    Print difficulty metrics (3BV, openings, \
+                                  islands, mine density)");
+                    println!("    of the layout in the board file given \
+                                  through --board instead of");
+                    println!("    playing it.");
+                    println!("");
+                    println!("  --simulate=<n>:");
+                    println!("    Let the automatic player play <n> games \
+                                  without opening a window,");
+                    println!("    and report how it fared.  The games use \
+                                  the seeds starting at");
+                    println!("    the one given through --seed (default: \
+                                  2:0).  This also works in");
+                    println!("    builds without the GUI (cargo build \
+                                  --no-default-features),");
+                    println!("    which do not need GTK.");
+                    println!("");
+                    println!("  --autoplay-delay=<ms>:");
+                    println!("    Let the automatic player (started and \
+                                  stopped by pressing 'a')");
+                    println!("    wait <ms> milliseconds between two moves \
+                                  (default: 200).");
 
                     return;
                 },
@@ -65,7 +381,59 @@ fn main() {
         }
     }
 
-    if free_args.len() > 0 {
+    let mask = mask_file.map(|path| {
+        topology::load_mask(&path).unwrap_or_else(|e| {
+            panic!("Failed to load mask file {}: {}", path, e)
+        })
+    });
+
+    if infinite {
+        if board_file.is_some() || mask.is_some() || target.is_some() ||
+           analyze || grid != Grid::Square || wrap || depth > 1 ||
+           neighborhood != Neighborhood::Moore
+        {
+            panic!("Infinite fields must be flat square grids without a \
+                    board file, mask or target difficulty");
+        }
+
+        // Keep roughly the default mine density
+        mine_count = 52;
+    }
+
+    if free_args.len() > 0 && board_file.is_some() {
+        panic!("Field dimensions cannot be specified with a board file");
+    }
+
+    if placement != Placement::Fixed && board_file.is_some() {
+        panic!("Mines loaded from a board file cannot be relocated");
+    }
+
+    if target.is_some() && board_file.is_some() {
+        panic!("Layouts loaded from a board file cannot be regenerated to \
+                meet a target difficulty");
+    }
+
+    if let Some(ref mask) = mask {
+        if board_file.is_some() {
+            panic!("A mask cannot be used with a board file");
+        }
+
+        width = mask[0].len();
+        height = mask.len();
+
+        // Keep roughly the default mine density
+        let cells = mask.iter().map(|r| r.iter().filter(|&&a| a).count())
+                               .sum::<usize>();
+        mine_count = cells * depth / 5;
+
+        if free_args.len() > 1 {
+            panic!("Only the mine count can be specified with a mask");
+        }
+
+        if free_args.len() == 1 {
+            mine_count = free_args[0].parse::<usize>().unwrap();
+        }
+    } else if free_args.len() > 0 {
         if free_args.len() < 3 {
             panic!("Either no or all of the field dimensions must be \
                     specified");
@@ -80,9 +448,82 @@ fn main() {
         }
     }
 
-    let game = Game::new((width, height), mine_count);
-    let logic = Logic::new(game, auto_unveil, touch_mode);
-    let gui = GUI::new(logic);
+    if analyze {
+        let path = board_file.unwrap_or_else(|| {
+            panic!("--analyze requires a board file")
+        });
+        let game = Game::load(&path).unwrap_or_else(|e| {
+            panic!("Failed to load board file {}: {}", path, e)
+        });
+
+        print!("{}", Analysis::new(&game).report());
+        return;
+    }
+
+    if let Some(ref mut target) = target {
+        if let Some(attempts) = attempts {
+            target.attempts = attempts;
+        }
+        target.time_limit = time_limit;
+    }
+
+    let new_topology = || {
+        let mut topology = Topology::new((width, height, depth), grid, wrap);
+        topology.set_neighborhood(neighborhood);
+        if let Some(ref mask) = mask {
+            topology.set_mask(mask.clone());
+        }
+        topology
+    };
+
+    if let Some(games) = simulate {
+        if board_file.is_some() || infinite {
+            panic!("Only random layouts on finite fields can be simulated");
+        }
+
+        let first_seed = seed.unwrap_or(0);
+        let simulation = Simulation::run(games, |i| {
+            let game = Game::new(new_topology(), mine_count, max_per_cell,
+                                 Some(first_seed.wrapping_add(i as u64)),
+                                 generator, first_click);
+            Logic::new(game, auto_unveil, touch_mode, target, placement)
+        });
+
+        print!("{}", simulation.report());
+        return;
+    }
+
+    let game = match board_file {
+        Some(path) => Game::load(&path).unwrap_or_else(|e| {
+            panic!("Failed to load board file {}: {}", path, e)
+        }),
+
+        None if infinite => {
+            Game::new(Topology::new_infinite(), mine_count, max_per_cell, seed,
+                      generator, first_click)
+        },
+
+        None => {
+            Game::new(new_topology(), mine_count, max_per_cell, seed,
+                      generator, first_click)
+        },
+    };
+
+    let mut logic = Logic::new(game, auto_unveil, touch_mode, target,
+                               placement);
+    logic.set_undo_mines(undo_mines);
+    play(logic, (width, height), autoplay_delay);
+}
+
+#[cfg(feature = "gui")]
+fn play(logic: Logic, view_size: (usize, usize), autoplay_delay: u32) {
+    let gui = GUI::new(logic, view_size, autoplay_delay);
 
     gui.main_loop();
 }
+
+#[cfg(not(feature = "gui"))]
+fn play(_: Logic, _: (usize, usize), _: u32) {
+    panic!("Built without the \"gui\" feature, so only --simulate is \
+            available");
+}
@@ -0,0 +1,47 @@
+// Portable pseudo-random number generator used for mine placement.
+//
+// Layouts are supposed to be reproducible from their seed on every
+// machine and with every release, so we cannot rely on the rand
+// crate's generators (whose output may change between versions).
+// Instead, this is SplitMix64 (as published by Sebastiano Vigna), and
+// its output must never change.
+
+#[derive(Clone, PartialEq)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 {
+            state: seed,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Returns a uniformly distributed number in [low, high).  To avoid
+    // modulo bias, values from the upper incomplete bucket are
+    // rejected and drawn again.
+    pub fn gen_range(&mut self, low: usize, high: usize) -> usize {
+        assert!(low < high);
+
+        let range = (high - low) as u64;
+        let zone = u64::MAX - u64::MAX % range;
+
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return low + (x % range) as usize;
+            }
+        }
+    }
+}
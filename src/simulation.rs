@@ -0,0 +1,64 @@
+// Statistics on how the automatic player fares over many games.
+
+use std::time::{Duration, Instant};
+
+use autoplay;
+use logic::Logic;
+
+
+pub struct Simulation {
+    pub games: usize,
+    pub won: usize,
+    // Totals over all games
+    pub guesses: usize,
+    pub bbbv: usize,
+    pub time: Duration,
+}
+
+
+impl Simulation {
+    // Lets the automatic player play @games games, the i-th of which is
+    // created by @new_game(i)
+    pub fn run<F: Fn(usize) -> Logic>(games: usize, new_game: F) -> Self {
+        let mut simulation = Simulation {
+            games: games,
+            won: 0,
+            guesses: 0,
+            bbbv: 0,
+            time: Duration::new(0, 0),
+        };
+
+        for i in 0..games {
+            let start = Instant::now();
+            let mut logic = new_game(i);
+            simulation.guesses += autoplay::play(&mut logic);
+            simulation.time += start.elapsed();
+
+            if logic.is_won() {
+                simulation.won += 1;
+            }
+            if let Some(analysis) = logic.analyze() {
+                simulation.bbbv += analysis.bbbv;
+            }
+        }
+
+        simulation
+    }
+
+    // Returns the results, one per line
+    pub fn report(&self) -> String {
+        // Avoid dividing by zero
+        let games = std::cmp::max(self.games, 1) as f64;
+
+        let mut text = format!("Games: {}\n", self.games);
+        text.push_str(&format!("Won: {} ({:.1}%)\n", self.won,
+                               self.won as f64 * 100.0 / games));
+        text.push_str(&format!("Guesses per game: {:.2}\n",
+                               self.guesses as f64 / games));
+        text.push_str(&format!("3BV per game: {:.1}\n",
+                               self.bbbv as f64 / games));
+        text.push_str(&format!("Time per game: {:.1} ms\n",
+                               self.time.as_secs_f64() * 1000.0 / games));
+        text
+    }
+}
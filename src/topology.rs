@@ -0,0 +1,344 @@
+// Describes the shape of the field: which cells exist and which cells
+// are each other's neighbors.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Position of a cell: x, y and the layer z (which is always 0 on flat
+// fields)
+pub type Pos = (usize, usize, usize);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Grid {
+    // Square cells (with eight neighbors each, unless another
+    // neighborhood is chosen)
+    Square,
+    // Hexagonal cells with six neighbors each.  Rows are offset against
+    // each other, every odd row is shifted to the right by half a cell.
+    Hex,
+}
+
+// Which cells count as neighbors on a square grid (hexagonal grids
+// always use the six adjacent cells)
+#[derive(Clone, Copy, PartialEq)]
+pub enum Neighborhood {
+    // The surrounding 3×3 block
+    Moore,
+    // Only the four orthogonally adjacent cells
+    Orthogonal,
+    // The eight cells a chess knight can move to
+    Knight,
+    // The surrounding 5×5 block
+    Radius2,
+}
+
+impl Neighborhood {
+    // Returns how far (in either direction) a neighbor can be away
+    pub fn reach(self) -> usize {
+        match self {
+            Neighborhood::Moore | Neighborhood::Orthogonal => 1,
+            Neighborhood::Knight | Neighborhood::Radius2   => 2,
+        }
+    }
+}
+
+
+pub struct Topology {
+    // Width, height and number of layers
+    dim: (usize, usize, usize),
+    grid: Grid,
+    neighborhood: Neighborhood,
+    // Whether the field wraps around at its edges (i.e. is a torus)
+    wrap: bool,
+    // Which positions actually hold a cell (None if all of them do);
+    // the same for every layer
+    mask: Option<Vec<Vec<bool>>>,
+    active_count: usize,
+    // Infinite fields have a huge (but, technically, finite) size, and
+    // play starts in their center
+    infinite: bool,
+}
+
+
+impl Topology {
+    // Fields with more than one layer are three-dimensional: Every
+    // cell neighbors the cells around it in its own layer and the cells
+    // around (and including) the one directly above and below, so 26
+    // in total.
+    pub fn new(dim: (usize, usize, usize), grid: Grid, wrap: bool) -> Self {
+        if dim.2 > 1 && grid != Grid::Square {
+            panic!("Only square grids can have multiple layers");
+        }
+
+        if wrap && dim.2 == 2 {
+            // Otherwise, the layer above would be the layer below
+            panic!("Fields that wrap around cannot have two layers");
+        }
+
+        if wrap && grid == Grid::Hex && dim.1 % 2 == 1 {
+            // Otherwise, the row offsets would not match up
+            panic!("Hexagonal fields that wrap around must have an even \
+                    number of rows");
+        }
+
+        Topology {
+            dim: dim,
+            grid: grid,
+            neighborhood: Neighborhood::Moore,
+            wrap: wrap,
+            mask: None,
+            active_count: dim.0 * dim.1 * dim.2,
+            infinite: false,
+        }
+    }
+
+    // Creates a flat square field without edges (for all practical
+    // purposes).  Its cells must not be enumerated, so per-cell data
+    // should be kept in a CellMap.
+    pub fn new_infinite() -> Self {
+        // Large enough to never be reached, small enough that offsets
+        // can be computed in i64
+        const EXTENT: usize = 1 << 32;
+
+        Topology {
+            dim: (EXTENT, EXTENT, 1),
+            grid: Grid::Square,
+            neighborhood: Neighborhood::Moore,
+            wrap: false,
+            mask: None,
+            active_count: usize::MAX,
+            infinite: true,
+        }
+    }
+
+    // Only possible for flat square grids
+    pub fn set_neighborhood(&mut self, neighborhood: Neighborhood) {
+        if self.grid != Grid::Square && neighborhood != Neighborhood::Moore {
+            panic!("Neighborhoods can only be chosen for square grids");
+        }
+
+        if self.dim.2 > 1 && neighborhood != Neighborhood::Moore {
+            panic!("Neighborhoods can only be chosen for flat fields");
+        }
+
+        let reach = neighborhood.reach();
+        if self.wrap && (self.dim.0 < 2 * reach + 1 ||
+                         self.dim.1 < 2 * reach + 1)
+        {
+            // Otherwise, cells would neighbor themselves or the same
+            // cell twice
+            panic!("Fields that wrap around must be at least {0}×{0} for \
+                    this neighborhood", 2 * reach + 1);
+        }
+
+        self.neighborhood = neighborhood;
+    }
+
+    // Removes all cells from the field whose entry in @mask is false
+    // (in every layer).  @mask must have the field's width and height.
+    pub fn set_mask(&mut self, mask: Vec<Vec<bool>>) {
+        assert!(mask.len() == self.dim.1 &&
+                mask.iter().all(|row| row.len() == self.dim.0));
+
+        self.active_count =
+            mask.iter().map(|row| row.iter().filter(|&&a| a).count())
+                       .sum::<usize>() * self.dim.2;
+        self.mask = Some(mask);
+    }
+
+    pub fn get_dim(&self) -> (usize, usize, usize) {
+        self.dim
+    }
+
+    pub fn get_grid(&self) -> Grid {
+        self.grid
+    }
+
+    pub fn get_neighborhood(&self) -> Neighborhood {
+        self.neighborhood
+    }
+
+    pub fn get_wrap(&self) -> bool {
+        self.wrap
+    }
+
+    // Returns all positions on the field (including those that hold no
+    // cell), layer by layer and row by row.  Must not be used for
+    // infinite fields.
+    pub fn positions(&self) -> impl Iterator<Item = Pos> {
+        assert!(!self.infinite);
+
+        let (w, h, d) = self.dim;
+        (0..(w * h * d)).map(move |i| (i % w, i / w % h, i / (w * h)))
+    }
+
+    // Returns the number of cells that actually exist (usize::MAX for
+    // infinite fields)
+    pub fn cell_count(&self) -> usize {
+        self.active_count
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        self.infinite
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.mask.is_some()
+    }
+
+    // Returns whether there is a cell at @pos
+    pub fn is_active(&self, pos: Pos) -> bool {
+        match self.mask {
+            Some(ref mask) => mask[pos.1][pos.0],
+            None           => true,
+        }
+    }
+
+    fn pos_in_bounds(&self, pos: (i64, i64, i64)) -> Option<Pos> {
+        if self.wrap {
+            let w = self.dim.0 as i64;
+            let h = self.dim.1 as i64;
+            let d = self.dim.2 as i64;
+            Some((((pos.0 % w + w) % w) as usize,
+                  ((pos.1 % h + h) % h) as usize,
+                  ((pos.2 % d + d) % d) as usize))
+        } else if pos.0 >= 0 && (pos.0 as usize) < self.dim.0 &&
+           pos.1 >= 0 && (pos.1 as usize) < self.dim.1 &&
+           pos.2 >= 0 && (pos.2 as usize) < self.dim.2
+        {
+            Some((pos.0 as usize, pos.1 as usize, pos.2 as usize))
+        } else {
+            None
+        }
+    }
+
+    // Returns how many neighbors a cell can have at most (cells at the
+    // edges or next to positions without a cell have fewer)
+    pub fn max_neighbor_count(&self) -> usize {
+        let in_layer = match self.grid {
+            Grid::Hex => 6,
+
+            Grid::Square => match self.neighborhood {
+                Neighborhood::Moore      => 8,
+                Neighborhood::Orthogonal => 4,
+                Neighborhood::Knight     => 8,
+                Neighborhood::Radius2    => 24,
+            },
+        };
+
+        if self.dim.2 > 1 {
+            // The cells above and below, and their neighbors
+            3 * in_layer + 2
+        } else {
+            in_layer
+        }
+    }
+
+    // Returns all neighbors of @pos (not including @pos itself)
+    pub fn neighbors(&self, pos: Pos) -> Vec<Pos> {
+        const SQUARE: [(i64, i64); 8] = [(-1, -1), ( 0, -1), ( 1, -1),
+                                         (-1,  0),           ( 1,  0),
+                                         (-1,  1), ( 0,  1), ( 1,  1)];
+
+        const ORTHOGONAL: [(i64, i64); 4] = [          ( 0, -1),
+                                             (-1,  0),           ( 1,  0),
+                                                       ( 0,  1)          ];
+
+        const KNIGHT: [(i64, i64); 8] = [(-1, -2), ( 1, -2),
+                                         (-2, -1), ( 2, -1),
+                                         (-2,  1), ( 2,  1),
+                                         (-1,  2), ( 1,  2)];
+
+        const RADIUS2: [(i64, i64); 24] =
+            [(-2, -2), (-1, -2), ( 0, -2), ( 1, -2), ( 2, -2),
+             (-2, -1), (-1, -1), ( 0, -1), ( 1, -1), ( 2, -1),
+             (-2,  0), (-1,  0),           ( 1,  0), ( 2,  0),
+             (-2,  1), (-1,  1), ( 0,  1), ( 1,  1), ( 2,  1),
+             (-2,  2), (-1,  2), ( 0,  2), ( 1,  2), ( 2,  2)];
+
+        const HEX_EVEN: [(i64, i64); 6] = [(-1, -1), ( 0, -1),
+                                           (-1,  0), ( 1,  0),
+                                           (-1,  1), ( 0,  1)];
+
+        const HEX_ODD: [(i64, i64); 6] = [( 0, -1), ( 1, -1),
+                                          (-1,  0), ( 1,  0),
+                                          ( 0,  1), ( 1,  1)];
+
+        let offsets: &[(i64, i64)] = match (self.grid, pos.1 % 2) {
+            (Grid::Hex, 0) => &HEX_EVEN,
+            (Grid::Hex, _) => &HEX_ODD,
+
+            (Grid::Square, _) => match self.neighborhood {
+                Neighborhood::Moore      => &SQUARE,
+                Neighborhood::Orthogonal => &ORTHOGONAL,
+                Neighborhood::Knight     => &KNIGHT,
+                Neighborhood::Radius2    => &RADIUS2,
+            },
+        };
+
+        // In the layers above and below, the cell directly adjacent is a
+        // neighbor, too
+        let layers: &[i64] = if self.dim.2 > 1 { &[-1, 0, 1] } else { &[0] };
+
+        let ipos = (pos.0 as i64, pos.1 as i64, pos.2 as i64);
+        let mut neighbors = Vec::new();
+        for &dz in layers {
+            if dz != 0 {
+                neighbors.extend(self.pos_in_bounds((ipos.0, ipos.1,
+                                                     ipos.2 + dz)));
+            }
+
+            neighbors.extend(
+                offsets.iter()
+                       .filter_map(|d| self.pos_in_bounds((ipos.0 + d.0,
+                                                           ipos.1 + d.1,
+                                                           ipos.2 + dz))));
+        }
+
+        neighbors.retain(|&npos| self.is_active(npos));
+        neighbors
+    }
+}
+
+
+// Loads a mask from a text file.  Every line is a row of the field, and
+// every character is a cell: '#' for a cell that exists, '-' or a space
+// for one that does not.  Lines shorter than the longest line are
+// padded with nonexistent cells.
+pub fn load_mask<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<bool>>, String> {
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+                    .map_err(|e| e.to_string())?;
+
+    let mut mask = Vec::<Vec<bool>>::new();
+    for (y, line) in text.lines().enumerate() {
+        let mut row = Vec::<bool>::new();
+        for c in line.trim_end().chars() {
+            match c {
+                '#'       => row.push(true),
+                '-' | ' ' => row.push(false),
+
+                _ => return Err(format!("Invalid character '{}' in row {}",
+                                        c, y + 1)),
+            }
+        }
+        mask.push(row);
+    }
+
+    // Drop empty rows at the end
+    while mask.last().is_some_and(|row| row.is_empty()) {
+        mask.pop();
+    }
+
+    let width = mask.iter().map(|row| row.len()).max().unwrap_or(0);
+    if width < 3 || mask.len() < 3 {
+        return Err(String::from("Field must be at least 3×3"));
+    }
+
+    for row in &mut mask {
+        row.resize(width, false);
+    }
+
+    Ok(mask)
+}